@@ -5,6 +5,12 @@ pub enum Error {
     Io(std::io::Error),
     /// The tags being read do not constitute a valid UTF-8 string.
     Utf8(std::string::FromUtf8Error),
+    /// The tags being written are too large to fit in the format.
+    TagsTooLarge,
+    /// The BMP DIB header has an unknown size, which is used to identify its version.
+    BmpUnsupportedHeader(u32),
+    /// The BMP pixel array uses a compression method whose size can't be determined.
+    BmpUnsupportedCompression(u32),
     /// An unknown GIF block was found. Possible blocks are:
     ///
     /// - Extension block (`0x21`)
@@ -13,8 +19,39 @@ pub enum Error {
     GifUnknownBlock(u8),
     /// An invalid JPEG marker was found. A marker can take any value except 0x00 and 0xFF.
     JpegInvalidMarker(u8),
+    /// An invalid EBML variable length integer was found, whose first byte has too many leading
+    /// zeros for its kind.
+    MatroskaInvalidVint(u8),
+    /// The ID3v2 version is either not valid or not supported for writing.
+    Mp3UnsupportedVersion(u8),
+    /// An Ogg page doesn't start with the `OggS` capture pattern, or has an unknown version.
+    OggInvalidPage,
+    /// The first Ogg logical stream is not a Vorbis, Opus or Theora stream with well-formed
+    /// headers.
+    OggUnsupportedStream,
+    /// The PDF file is encrypted, so tags can't be written to it.
+    PdfEncrypted,
+    /// The PDF file has a malformed object, cross-reference section or trailer.
+    PdfInvalidSyntax,
+    /// A PDF cross-reference or object stream uses an unsupported filter or predictor.
+    PdfUnsupportedStream,
     /// There is a mismatch between the calculated CRC-32 hash and the one found in the block.
     PngChecksum(u32, u32),
+    /// The QOI chunks aren't followed by the end marker.
+    QoiMissingEndMarker,
+    /// An RF64 or BW64 file doesn't start with the `ds64` chunk that holds its sizes.
+    RiffMissingDs64,
+    /// The SVG document is malformed before its tags, or its root element isn't `<svg>`.
+    SvgInvalidDocument,
+    /// The tags being written contain a character that can't be represented in XML.
+    SvgInvalidCharacter(char),
+    /// The XCF version is either not valid or too recent to be written safely.
+    XcfUnsupportedVersion([u8; 4]),
+    /// The ZIP end of central directory record can't be found, or the central directory is
+    /// malformed.
+    ZipInvalidArchive,
+    /// The ZIP archive spans multiple disks, uses ZIP64 extensions, or has a compressed tags entry.
+    ZipUnsupportedArchive,
 }
 
 impl From<std::io::Error> for Error {
@@ -34,9 +71,28 @@ impl std::fmt::Display for Error {
         match self {
             Error::Io(e) => write!(f, "io error: {e}"),
             Error::Utf8(e) => write!(f, "tags are not valid utf-8: {e}"),
-            Error::GifUnknownBlock(b) => write!(f, "unknown gif block found: {b:02X}",),
+            Error::TagsTooLarge => write!(f, "tags are too large for the format"),
+            Error::BmpUnsupportedHeader(s) => write!(f, "unsupported bmp header size: {s}"),
+            Error::BmpUnsupportedCompression(c) => write!(f, "unsupported bmp compression: {c}"),
+            Error::GifUnknownBlock(b) => write!(f, "unknown gif block found: {b:02X}"),
             Error::JpegInvalidMarker(b) => write!(f, "invalid jpeg marker found: {b:02X}"),
+            Error::MatroskaInvalidVint(b) => write!(f, "invalid matroska vint found: {b:02X}"),
+            Error::Mp3UnsupportedVersion(v) => write!(f, "unsupported id3v2 version: {v}"),
+            Error::OggInvalidPage => write!(f, "invalid ogg page found"),
+            Error::OggUnsupportedStream => write!(f, "unsupported ogg stream"),
+            Error::PdfEncrypted => write!(f, "encrypted pdf files are not supported"),
+            Error::PdfInvalidSyntax => write!(f, "invalid pdf syntax found"),
+            Error::PdfUnsupportedStream => write!(f, "unsupported pdf stream encoding"),
             Error::PngChecksum(a, b) => write!(f, "corrupted tags in png data: {a:04X} != {b:04X}"),
+            Error::QoiMissingEndMarker => write!(f, "missing qoi end marker"),
+            Error::RiffMissingDs64 => write!(f, "missing ds64 chunk in rf64 file"),
+            Error::SvgInvalidDocument => write!(f, "invalid svg document"),
+            Error::SvgInvalidCharacter(c) => write!(f, "invalid xml character: {:04X}", *c as u32),
+            Error::XcfUnsupportedVersion(v) => {
+                write!(f, "unsupported xcf version: {}", String::from_utf8_lossy(v))
+            }
+            Error::ZipInvalidArchive => write!(f, "invalid zip archive"),
+            Error::ZipUnsupportedArchive => write!(f, "unsupported zip archive"),
         }
     }
 }
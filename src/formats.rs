@@ -1,86 +1,267 @@
+#[cfg(feature = "bmp")]
+pub mod bmp;
+#[cfg(feature = "flac")]
+pub mod flac;
 #[cfg(feature = "gif")]
 pub mod gif;
+#[cfg(feature = "ico")]
+pub mod ico;
 #[cfg(feature = "isobmff")]
 pub mod isobmff;
 #[cfg(feature = "jpeg")]
 pub mod jpeg;
+#[cfg(feature = "jxl")]
+pub mod jxl;
+#[cfg(feature = "matroska")]
+pub mod matroska;
+#[cfg(feature = "mp3")]
+pub mod mp3;
+#[cfg(feature = "ogg")]
+pub mod ogg;
+#[cfg(feature = "pdf")]
+pub mod pdf;
 #[cfg(feature = "png")]
 pub mod png;
+#[cfg(feature = "psd")]
+pub mod psd;
+#[cfg(feature = "qoi")]
+pub mod qoi;
 #[cfg(feature = "riff")]
 pub mod riff;
+#[cfg(feature = "svg")]
+pub mod svg;
+#[cfg(feature = "tiff")]
+pub mod tiff;
+#[cfg(feature = "xcf")]
+pub mod xcf;
+#[cfg(feature = "zip")]
+pub mod zip;
 
-use crate::utils::{or_eof, read_byte, read_heap};
+use crate::utils::{or_eof, read_byte};
 use std::io::Read;
 
 /// One of the possible formats identified by [`identify_format`][crate::identify_format].
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Format {
+    /// [Windows Bitmap][crate::bmp].
+    #[cfg(feature = "bmp")]
+    Bmp,
+    /// [Free Lossless Audio Codec][crate::flac].
+    #[cfg(feature = "flac")]
+    Flac,
     /// [Graphics Interchange Format][crate::gif].
     #[cfg(feature = "gif")]
     Gif,
+    /// [Windows Icon][crate::ico], including cursors.
+    #[cfg(feature = "ico")]
+    Ico,
     /// [ISO Base Media File Format][crate::isobmff].
     #[cfg(feature = "isobmff")]
     Isobmff,
     /// [Joint Photographic Experts Group][crate::jpeg].
     #[cfg(feature = "jpeg")]
     Jpeg,
+    /// [JPEG XL][crate::jxl].
+    #[cfg(feature = "jxl")]
+    Jxl,
+    /// [Matroska][crate::matroska].
+    #[cfg(feature = "matroska")]
+    Matroska,
+    /// [MPEG-1 Audio Layer III][crate::mp3].
+    #[cfg(feature = "mp3")]
+    Mp3,
+    /// [Ogg][crate::ogg].
+    #[cfg(feature = "ogg")]
+    Ogg,
+    /// [Portable Document Format][crate::pdf].
+    #[cfg(feature = "pdf")]
+    Pdf,
     /// [Portable Network Graphics][crate::png].
     #[cfg(feature = "png")]
     Png,
-    /// [Resource Interchange File Format][crate::riff].
+    /// [Photoshop Document][crate::psd].
+    #[cfg(feature = "psd")]
+    Psd,
+    /// [Quite OK Image Format][crate::qoi].
+    #[cfg(feature = "qoi")]
+    Qoi,
+    /// [Resource Interchange File Format][crate::riff], including IFF.
     #[cfg(feature = "riff")]
     Riff,
+    /// [Scalable Vector Graphics][crate::svg].
+    #[cfg(feature = "svg")]
+    Svg,
+    /// [Tagged Image File Format][crate::tiff].
+    #[cfg(feature = "tiff")]
+    Tiff,
+    /// [eXperimental Computing Facility][crate::xcf].
+    #[cfg(feature = "xcf")]
+    Xcf,
+    /// [ZIP][crate::zip] archives, including formats based on them.
+    #[cfg(feature = "zip")]
+    Zip,
 }
 
+/// Extra check for formats whose magic number is too weak on its own. It receives the bytes up to
+/// the end of the magic number, and can keep reading whatever follows them if it needs to.
+type Validator = fn(&[u8], &mut dyn Read) -> Result<bool, std::io::Error>;
+
 #[derive(Copy, Clone, Debug)]
 struct FormatInfo {
     magic: &'static [u8],
     offset: usize,
     format: Format,
+    validator: Option<Validator>,
 }
 
 impl FormatInfo {
     const fn new(magic: &'static [u8], offset: usize, format: Format) -> Self {
-        Self { magic, offset, format }
+        Self { magic, offset, format, validator: None }
+    }
+
+    const fn validated(self, validator: Validator) -> Self {
+        Self { validator: Some(validator), ..self }
+    }
+}
+
+/// Reads from `src` while keeping everything in `buf`, so the same bytes can be read more than
+/// once.
+struct Replay<'a, R> {
+    src: &'a mut R,
+    buf: &'a mut Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for Replay<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.buf.len() {
+            (&mut *self.src).take(out.len() as u64).read_to_end(self.buf)?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
 }
 
 const FORMATS: &[FormatInfo] = &[
+    #[cfg(feature = "bmp")]
+    FormatInfo::new(bmp::MAGIC, bmp::OFFSET, Format::Bmp),
+    #[cfg(feature = "flac")]
+    FormatInfo::new(flac::MAGIC, flac::OFFSET, Format::Flac),
     #[cfg(feature = "gif")]
     FormatInfo::new(gif::MAGIC, gif::OFFSET, Format::Gif),
+    #[cfg(feature = "gif")]
+    FormatInfo::new(gif::MAGIC_87A, gif::OFFSET, Format::Gif),
+    #[cfg(feature = "ico")]
+    FormatInfo::new(ico::MAGIC, ico::OFFSET, Format::Ico).validated(ico::is_ico),
+    #[cfg(feature = "ico")]
+    FormatInfo::new(ico::CUR_MAGIC, ico::OFFSET, Format::Ico).validated(ico::is_ico),
     #[cfg(feature = "isobmff")]
     FormatInfo::new(isobmff::MAGIC, isobmff::OFFSET, Format::Isobmff),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::JP2_MAGIC, isobmff::JP2_OFFSET, Format::Isobmff),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::QT_MAGIC[0], isobmff::OFFSET, Format::Isobmff)
+        .validated(isobmff::is_quicktime),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::QT_MAGIC[1], isobmff::OFFSET, Format::Isobmff)
+        .validated(isobmff::is_quicktime),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::QT_MAGIC[2], isobmff::OFFSET, Format::Isobmff)
+        .validated(isobmff::is_quicktime),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::QT_MAGIC[3], isobmff::OFFSET, Format::Isobmff)
+        .validated(isobmff::is_quicktime),
+    #[cfg(feature = "isobmff")]
+    FormatInfo::new(isobmff::QT_MAGIC[4], isobmff::OFFSET, Format::Isobmff)
+        .validated(isobmff::is_quicktime),
     #[cfg(feature = "jpeg")]
     FormatInfo::new(jpeg::MAGIC, jpeg::OFFSET, Format::Jpeg),
+    #[cfg(feature = "jxl")]
+    FormatInfo::new(jxl::MAGIC, jxl::OFFSET, Format::Jxl),
+    #[cfg(feature = "jxl")]
+    FormatInfo::new(jxl::CODESTREAM_MAGIC, jxl::OFFSET, Format::Jxl),
+    #[cfg(feature = "matroska")]
+    FormatInfo::new(matroska::MAGIC, matroska::OFFSET, Format::Matroska),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MAGIC, mp3::OFFSET, Format::Mp3),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[0], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[1], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[2], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[3], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[4], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "mp3")]
+    FormatInfo::new(mp3::MPEG_MAGIC[5], mp3::OFFSET, Format::Mp3).validated(mp3::is_mpeg),
+    #[cfg(feature = "ogg")]
+    FormatInfo::new(ogg::MAGIC, ogg::OFFSET, Format::Ogg),
+    #[cfg(feature = "pdf")]
+    FormatInfo::new(pdf::MAGIC, pdf::OFFSET, Format::Pdf),
     #[cfg(feature = "png")]
     FormatInfo::new(png::MAGIC, png::OFFSET, Format::Png),
+    #[cfg(feature = "psd")]
+    FormatInfo::new(psd::MAGIC, psd::OFFSET, Format::Psd),
+    #[cfg(feature = "qoi")]
+    FormatInfo::new(qoi::MAGIC, qoi::OFFSET, Format::Qoi),
     #[cfg(feature = "riff")]
     FormatInfo::new(riff::MAGIC, riff::OFFSET, Format::Riff),
+    #[cfg(feature = "riff")]
+    FormatInfo::new(riff::FORM_MAGIC, riff::OFFSET, Format::Riff),
+    #[cfg(feature = "riff")]
+    FormatInfo::new(riff::RF64_MAGIC, riff::OFFSET, Format::Riff),
+    #[cfg(feature = "riff")]
+    FormatInfo::new(riff::BW64_MAGIC, riff::OFFSET, Format::Riff),
+    #[cfg(feature = "svg")]
+    FormatInfo::new(svg::MAGIC, svg::OFFSET, Format::Svg).validated(svg::is_svg),
+    #[cfg(feature = "svg")]
+    FormatInfo::new(svg::BOM_MAGIC, svg::OFFSET, Format::Svg).validated(svg::is_svg),
+    #[cfg(feature = "tiff")]
+    FormatInfo::new(tiff::LE_MAGIC, tiff::OFFSET, Format::Tiff),
+    #[cfg(feature = "tiff")]
+    FormatInfo::new(tiff::BE_MAGIC, tiff::OFFSET, Format::Tiff),
+    #[cfg(feature = "xcf")]
+    FormatInfo::new(xcf::MAGIC, xcf::OFFSET, Format::Xcf),
+    #[cfg(feature = "zip")]
+    FormatInfo::new(zip::MAGIC, zip::OFFSET, Format::Zip),
 ];
 
 /// Attempts to identify the format of a given `src`.
 ///
 /// The function operates based on a list of known "magic numbers" that can be found near the
-/// beginning of most file formats.
+/// beginning of most file formats. Formats with weaker magic numbers are further validated by
+/// looking at the data that follows.
 ///
 /// If no known format can be identified, `None` will be returned.
 pub fn identify_format(src: &mut impl Read) -> Result<Option<Format>, std::io::Error> {
-    let mut active = Vec::new();
-    let mut next = FORMATS.to_vec();
-    let mut i = 0;
-    while let Some(byte) = or_eof(read_byte(src))? {
-        let (new, still_next) = next.into_iter().partition(|f| f.offset == i);
-        next = still_next;
-        active = active.into_iter().chain(new).filter(|f| byte == f.magic[i - f.offset]).collect();
-        i += 1;
-        match active.len() {
-            1 => {
-                let FormatInfo { magic, offset, format } = active[0];
-                let rest = read_heap(src, magic.len() + offset - i)?;
-                return Ok((rest == magic[i - offset..]).then_some(format));
+    let mut candidates = FORMATS.to_vec();
+    let mut prefix = Vec::new();
+    // Validated formats wait until every longer magic number has been ruled out
+    let mut pending = Vec::new();
+    while !candidates.is_empty() {
+        let Some(byte) = or_eof(read_byte(src))? else {
+            break;
+        };
+        let i = prefix.len();
+        candidates.retain(|f| i < f.offset || byte == f.magic[i - f.offset]);
+        prefix.push(byte);
+        for f in candidates.iter().filter(|f| f.offset + f.magic.len() == prefix.len()) {
+            match f.validator {
+                Some(validator) => pending.push((f.format, validator, prefix.len())),
+                None => return Ok(Some(f.format)),
             }
-            0 if next.is_empty() => return Ok(None), // TODO: skip useless bytes
-            _ => continue,
+        }
+        candidates.retain(|f| f.offset + f.magic.len() > prefix.len());
+    }
+    // Longer magic numbers are more specific, so they get validated first
+    for (format, validator, end) in pending.into_iter().rev() {
+        let header = prefix[..end].to_vec();
+        if validator(&header, &mut Replay { src, buf: &mut prefix, pos: end })? {
+            return Ok(Some(format));
         }
     }
     Ok(None)
@@ -92,13 +273,35 @@ mod tests {
 
     #[test]
     fn correctly_identify_handlers() {
-        for format in FORMATS {
+        for format in FORMATS.iter().filter(|f| f.validator.is_none()) {
             let mut bytes = vec![0; format.offset];
             bytes.extend_from_slice(format.magic);
             assert_eq!(identify_format(&mut &bytes[..]).unwrap(), Some(format.format));
         }
     }
 
+    #[test]
+    fn overlapping_magic() {
+        let bytes = &[&0x0Cu32.to_be_bytes()[..], b"ftyp"].concat();
+        assert_eq!(identify_format(&mut &bytes[..]).unwrap(), Some(Format::Isobmff));
+    }
+
+    #[test]
+    fn pending_validators() {
+        // Shorter validated magic numbers shouldn't prevent longer ones from being checked
+        let bytes = &[&256u32.to_be_bytes()[..], b"moov", &16u32.to_be_bytes(), b"mvhd"].concat();
+        assert_eq!(identify_format(&mut &bytes[..]).unwrap(), Some(Format::Isobmff));
+    }
+
+    #[test]
+    fn rejected_weak_magic() {
+        // ISOBMFF files whose first box is 256 or 512 bytes long start with the ICO/CUR magic
+        for size in [256u32, 512] {
+            let bytes = &[&size.to_be_bytes()[..], b"ftypisom", &[0; 244]].concat();
+            assert_eq!(identify_format(&mut &bytes[..]).unwrap(), Some(Format::Isobmff));
+        }
+    }
+
     #[test]
     fn unknown_format() {
         let bytes = &[0x2E, 0x7C, 0x2E, 0x2E, 0x0A, 0x2E, 0x2E, 0x7C, 0x2E, 0x2C];
@@ -0,0 +1,230 @@
+//! # Windows Bitmap
+//!
+//! BMP files start with a 14 byte file header, structured as follows:
+//!
+//! - 2 byte `BM` magic number.
+//! - 4 byte little endian size of the whole file, `bfSize`.
+//! - 4 reserved bytes.
+//! - 4 byte little endian offset of the pixel array, `bfOffBits`.
+//!
+//! This is followed by a DIB header, whose first 4 bytes describe its size, which in turn
+//! identifies its version. The oldest one is the 12 byte `BITMAPCOREHEADER`, but most files use the
+//! 40 byte `BITMAPINFOHEADER`, or one of its extensions up to the 124 byte `BITMAPV5HEADER`. After
+//! the DIB header come the optional color masks and color table, and then the pixel array. The
+//! size of the pixel array can be calculated from the dimensions of the image for uncompressed
+//! bitmaps, while compressed ones store it in the header. A `BITMAPV5HEADER` may also point to an
+//! embedded ICC profile, which is usually stored after the pixel array.
+//!
+//! MemeDB stores its tags right after the pixel array and the ICC profile, prefixed by the
+//! `MemeDB\0` identifier, and then updates `bfSize` to account for them. Since nothing is ever
+//! inserted before the pixel array or the ICC profile, `bfOffBits` and the profile offset stay
+//! valid.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for BMP](https://en.wikipedia.org/wiki/BMP_file_format)
+//! - [`BITMAPV5HEADER` documentation](https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapv5header)
+//! - [Bitmap storage documentation](https://learn.microsoft.com/en-us/windows/win32/gdi/bitmap-storage)
+
+pub(crate) const MAGIC: &[u8] = b"BM";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_heap, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_ID: &[u8; 7] = b"MemeDB\x00";
+
+const CORE_HEADER_SIZE: u32 = 12;
+const HEADER_SIZES: [u32; 8] = [CORE_HEADER_SIZE, 16, 40, 52, 56, 64, 108, 124];
+const V5_HEADER_SIZE: u32 = 124;
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+const BI_CMYK: u32 = 11;
+
+const PROFILE_LINKED: u32 = u32::from_be_bytes(*b"LINK");
+const PROFILE_EMBEDDED: u32 = u32::from_be_bytes(*b"MBED");
+
+fn u16_at(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+/// Skips over a run-length encoded pixel array, returning its size.
+fn rle_size(src: &mut (impl Read + Seek), four_bit: bool) -> Result<u64, std::io::Error> {
+    let start = src.stream_position()?;
+    loop {
+        match read_stack::<2>(src)? {
+            // End of bitmap
+            [0, 1] => break,
+            // Delta, followed by two bytes of offsets
+            [0, 2] => {
+                skip(src, 2)?;
+            }
+            // Absolute mode, where the pixels are stored as is, padded to a 16 bit boundary
+            [0, n @ 3..=u8::MAX] => {
+                let size = if four_bit { (n as i64 + 1) / 2 } else { n as i64 };
+                skip(src, size + (size & 1))?;
+            }
+            // End of line, or an encoded run
+            _ => (),
+        }
+    }
+    Ok(src.stream_position()? - start)
+}
+
+/// Finds where the pixel array and the ICC profile end, relative to the start of the file.
+fn data_end(src: &mut (impl Read + Seek)) -> Result<u64, Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))? - start;
+    src.seek(SeekFrom::Start(start))?;
+    let file_header = read_stack::<14>(src)?;
+    let pixels_offset = u32_at(&file_header, 10) as u64;
+    let header_size = u32::from_le_bytes(read_stack::<4>(src)?);
+    if !HEADER_SIZES.contains(&header_size) {
+        return Err(Error::BmpUnsupportedHeader(header_size));
+    }
+    let header =
+        [&header_size.to_le_bytes()[..], &read_heap(src, header_size as usize - 4)?].concat();
+
+    let (width, height, bit_count) = if header_size == CORE_HEADER_SIZE {
+        (u16_at(&header, 4) as u64, u16_at(&header, 6) as u64, u16_at(&header, 10))
+    } else {
+        let height = i32::from_le_bytes(header[8..12].try_into().unwrap());
+        (u32_at(&header, 4) as u64, height.unsigned_abs() as u64, u16_at(&header, 14))
+    };
+    let compression = if header_size >= 20 { u32_at(&header, 16) } else { BI_RGB };
+    let size_image = if header_size >= 24 { u32_at(&header, 20) } else { 0 };
+
+    let pixels_size = match compression {
+        BI_RGB | BI_BITFIELDS | BI_ALPHABITFIELDS | BI_CMYK => {
+            // Rows are padded to a 32 bit boundary
+            let stride = (width * bit_count as u64).div_ceil(32) * 4;
+            stride.checked_mul(height)
+        }
+        _ if size_image != 0 => Some(size_image as u64),
+        BI_RLE8 | BI_RLE4 => {
+            src.seek(SeekFrom::Start(start + pixels_offset))?;
+            Some(rle_size(src, compression == BI_RLE4)?)
+        }
+        _ => return Err(Error::BmpUnsupportedCompression(compression)),
+    };
+    // The headers themselves are always kept, even if the pixel array is said to overlap them
+    let headers_end = 14 + header_size as u64;
+    let mut end = pixels_size.and_then(|size| size.checked_add(pixels_offset.max(headers_end)));
+
+    if header_size == V5_HEADER_SIZE {
+        let cs_type = u32_at(&header, 56);
+        if cs_type == PROFILE_LINKED || cs_type == PROFILE_EMBEDDED {
+            // The profile offset is relative to the start of the DIB header
+            let profile_end = 14 + u32_at(&header, 112) as u64 + u32_at(&header, 116) as u64;
+            end = end.map(|end| end.max(profile_end));
+        }
+    }
+    match end {
+        Some(end) if end <= len => Ok(end),
+        _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
+    }
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let start = src.stream_position()?;
+    let end = data_end(src)?;
+    src.seek(SeekFrom::Start(start + end))?;
+    match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => decode_tags(src),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))? - start;
+    src.seek(SeekFrom::Start(start))?;
+    let end = data_end(src)?;
+
+    // Any data following the old tags is kept after the new ones
+    src.seek(SeekFrom::Start(start + end))?;
+    let rest = match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => {
+            decode_tags(src)?;
+            src.stream_position()?
+        }
+        _ => start + end,
+    };
+
+    let mut tags_bytes = TAGS_ID.to_vec();
+    encode_tags(tags, &mut tags_bytes)?;
+    let size = end + tags_bytes.len() as u64 + (len - (rest - start));
+    let size = u32::try_from(size).map_err(|_| Error::TagsTooLarge)?;
+
+    src.seek(SeekFrom::Start(start))?;
+    passthrough(src, dest, 2)?;
+    skip(src, 4)?;
+    dest.write_all(&size.to_le_bytes())?;
+    passthrough(src, dest, end - 6)?;
+    dest.write_all(&tags_bytes)?;
+    src.seek(SeekFrom::Start(rest))?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const FILE_HEADER: &[&[u8]] = &[MAGIC, &[0; 8], &[26, 0, 0, 0]];
+    // A 1x1 pixel, 1 bit per pixel image, with a single 4 byte row
+    const CORE_HEADER: &[&[u8]] = &[&[12, 0, 0, 0], &[1, 0, 1, 0], &[1, 0, 1, 0]];
+    const PIXELS: &[u8] = &[0x80, 0, 0, 0];
+    const TAGS: &[&[u8]] = &[TAGS_ID, &[0x83, b'f', b'o', b'o', 0x00]];
+
+    #[test]
+    fn trailing_data() {
+        let src = &[FILE_HEADER, CORE_HEADER, &[PIXELS, b"junk"], TAGS].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let header: &[&[u8]] = &[MAGIC, &[0x3A, 0, 0, 0], &[0; 4], &[26, 0, 0, 0]];
+        let expected = &[header, CORE_HEADER, &[PIXELS], TAGS, &[b"junk"], TAGS].concat().concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn rle_pixels() {
+        let header: &[&[u8]] = &[&[40, 0, 0, 0], &[2, 0, 0, 0], &[1, 0, 0, 0], &[1, 0, 8, 0]];
+        let header = &[header, &[&[BI_RLE8 as u8, 0, 0, 0], &[0; 20]]].concat();
+        let pixels: &[u8] = &[0, 3, 1, 2, 3, 0, 0, 0, 0, 1];
+        let file_header: &[&[u8]] = &[MAGIC, &[0; 8], &[54, 0, 0, 0]];
+        let src = &[file_header, header, &[pixels], TAGS].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn unsupported_header() {
+        let src = &[FILE_HEADER, &[&[13, 0, 0, 0], &[0; 9]]].concat().concat();
+        let result = read_tags(&mut Cursor::new(src));
+        assert!(matches!(result, Err(Error::BmpUnsupportedHeader(13))));
+    }
+}
+
+crate::utils::standard_tests!("bmp");
@@ -0,0 +1,202 @@
+//! # Free Lossless Audio Codec
+//!
+//! FLAC files start with the `fLaC` magic number, followed by a series of metadata blocks and then
+//! the audio frames. Each metadata block is structured as follows:
+//!
+//! - 1 byte header, whose highest bit marks the last metadata block, and whose other 7 bits
+//!   describe the block type.
+//! - 3 byte big endian number describing the length of the data within.
+//! - The block data itself.
+//!
+//! The first block must be a `STREAMINFO` block. Other blocks include `PADDING` blocks, which are
+//! filled with zeros and reserve space for later metadata edits, and `APPLICATION` blocks, which
+//! start with a 4 byte application ID.
+//!
+//! MemeDB stores its tags in an `APPLICATION` block with the ID `meme`, placed after every other
+//! block except padding. If the file has `PADDING` blocks, they are merged into one and shrunk or
+//! grown to make room for the tags, so that the audio frames stay at the same offset whenever
+//! possible.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for FLAC](https://en.wikipedia.org/wiki/FLAC)
+//! - [RFC 9639, the FLAC specification](https://www.rfc-editor.org/rfc/rfc9639)
+
+pub(crate) const MAGIC: &[u8] = b"fLaC";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, passthrough, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_ID: &[u8; 4] = b"meme";
+
+const LAST_BLOCK: u8 = 0x80;
+const PADDING: u8 = 1;
+const APPLICATION: u8 = 2;
+
+const MAX_BLOCK_SIZE: u32 = 0xFFFFFF;
+
+struct Block {
+    r#type: u8,
+    last: bool,
+    size: u32,
+}
+
+impl Block {
+    fn read(src: &mut impl Read) -> Result<Self, std::io::Error> {
+        let header = u32::from_be_bytes(read_stack::<4>(src)?);
+        let r#type = (header >> 24) as u8;
+        Ok(Self {
+            r#type: r#type & !LAST_BLOCK,
+            last: r#type & LAST_BLOCK != 0,
+            size: header & MAX_BLOCK_SIZE,
+        })
+    }
+
+    fn write(&self, dest: &mut impl Write) -> Result<(), std::io::Error> {
+        let r#type = if self.last { self.r#type | LAST_BLOCK } else { self.r#type };
+        dest.write_all(&((r#type as u32) << 24 | self.size).to_be_bytes())
+    }
+}
+
+/// Reads the application ID of a block if it belongs to MemeDB, leaving `src` at the tags.
+fn is_tags_block(src: &mut impl Read, block: &Block) -> Result<bool, std::io::Error> {
+    Ok(block.r#type == APPLICATION && block.size >= 4 && &read_stack::<4>(src)? == TAGS_ID)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    skip(src, MAGIC.len() as i64)?;
+    loop {
+        let block = Block::read(src)?;
+        let start = src.stream_position()?;
+        if is_tags_block(src, &block)? {
+            return decode_tags(&mut src.take(block.size as u64 - 4));
+        }
+        if block.last {
+            return Ok(Vec::new());
+        }
+        src.seek(SeekFrom::Start(start + block.size as u64))?;
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start + MAGIC.len() as u64))?;
+
+    // A first pass finds out which blocks are kept, and how much space can be reused
+    let mut kept = Vec::new();
+    let mut padding = None;
+    let mut reusable = 0;
+    loop {
+        let position = src.stream_position()?;
+        let block = Block::read(src)?;
+        let next = src.stream_position()? + block.size as u64;
+        if next > len {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        if block.r#type == PADDING {
+            *padding.get_or_insert(0) += 4 + block.size as u64;
+        } else if is_tags_block(src, &block)? {
+            reusable += 4 + block.size as u64;
+        } else {
+            kept.push(position);
+        }
+        src.seek(SeekFrom::Start(next))?;
+        if block.last {
+            break;
+        }
+    }
+    let audio_start = src.stream_position()?;
+
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+    let tags_size = u32::try_from(4 + tags_bytes.len())
+        .ok()
+        .filter(|&size| size <= MAX_BLOCK_SIZE)
+        .ok_or(Error::TagsTooLarge)?;
+    // Without enough room, all of the space reserved before is kept as padding
+    let padding = padding.map(|padding| {
+        let available = padding + reusable;
+        match 4 + tags_size as u64 {
+            needed if needed == available => 0,
+            needed if needed + 4 <= available => available - needed,
+            _ => available,
+        }
+    });
+
+    dest.write_all(MAGIC)?;
+    for position in kept {
+        src.seek(SeekFrom::Start(position))?;
+        let mut block = Block::read(src)?;
+        block.last = false;
+        block.write(dest)?;
+        passthrough(src, dest, block.size as u64)?;
+    }
+    let last = matches!(padding, None | Some(0));
+    Block { r#type: APPLICATION, last, size: tags_size }.write(dest)?;
+    dest.write_all(TAGS_ID)?;
+    dest.write_all(&tags_bytes)?;
+    if let Some(mut remaining) = padding {
+        // Blocks have a maximum size, so a lot of padding may need more than one block
+        while remaining > 0 {
+            let mut size = (remaining - 4).min(MAX_BLOCK_SIZE as u64);
+            if (1..4).contains(&(remaining - 4 - size)) {
+                size -= 4;
+            }
+            remaining -= 4 + size;
+            Block { r#type: PADDING, last: remaining == 0, size: size as u32 }.write(dest)?;
+            std::io::copy(&mut std::io::repeat(0).take(size), dest)?;
+        }
+    }
+
+    src.seek(SeekFrom::Start(audio_start))?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const STREAMINFO: &[&[u8]] = &[&[0x00, 0, 0, 4], &[0; 4]];
+    const LAST_STREAMINFO: &[&[u8]] = &[&[0x80, 0, 0, 4], &[0; 4]];
+    const PADDING_BLOCK: &[&[u8]] = &[&[0x81, 0, 0, 16], &[0; 16]];
+    const TAGS: &[&[u8]] = &[&[0x02, 0, 0, 9], TAGS_ID, &[0x83, b'f', b'o', b'o', 0x00]];
+    const LAST_TAGS: &[&[u8]] = &[&[0x82, 0, 0, 9], TAGS_ID, &[0x83, b'f', b'o', b'o', 0x00]];
+    const FRAME: &[u8] = &[0xFF, 0xF8];
+
+    #[test]
+    fn last_block_flag() {
+        let src = &[MAGIC, &LAST_STREAMINFO.concat(), FRAME].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let expected = &[MAGIC, &STREAMINFO.concat(), &LAST_TAGS.concat(), FRAME].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn use_padding() {
+        let src = &[MAGIC, &STREAMINFO.concat(), &PADDING_BLOCK.concat(), FRAME].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let padding: &[&[u8]] = &[&[0x81, 0, 0, 3], &[0; 3]];
+        let expected = &[MAGIC, &STREAMINFO.concat(), &TAGS.concat(), &padding.concat(), FRAME];
+        assert_eq!(dest, expected.concat());
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+}
+
+crate::utils::standard_tests!("flac");
@@ -3,17 +3,18 @@
 //! GIF files are organized as a sequence of descriptors, extensions, and image data:
 //!
 //! - A Logical Screen Descriptor must be at the beginning of the file, it has a fixed sized and
-//! may be followed by an optional color table.
+//!   may be followed by an optional color table.
 //! - Extensions are identified by a `0x21` byte, followed by a label byte and a series of
-//! sub-blocks.
+//!   sub-blocks.
 //! - Image Descriptors start with a `0x2C` byte have a fixed size and are followed by an optional
-//! color table and a series of sub-blocks.
+//!   color table and a series of sub-blocks.
 //! - Sub-blocks indicate their size in a single byte, followed by their data. A sequence of
-//! sub-blocks ends when a sub-block of size 0 is found.
+//!   sub-blocks ends when a sub-block of size 0 is found.
 //! - The file ends when a trailer block is found, indicated by a single `0x3B` byte.
 //!
 //! GIF files start with a fixed-length header (`GIF87a` or `GIF89a`) marking which version of the
-//! spec is used. This library only handles the `GIF89a` spec.
+//! spec is used. Both versions can be read, but since extensions were introduced in `GIF89a`, the
+//! header of `GIF87a` files is upgraded when writing tags.
 //!
 //! MemeDB stores its tags in an Application Extension with the label `MEMETAGS1.0`.
 //!
@@ -24,6 +25,7 @@
 //! - [Matthew Flickinger's "What's In A GIF"](https://www.matthewflickinger.com/lab/whatsinagif/)
 
 pub(crate) const MAGIC: &[u8] = b"GIF89a";
+pub(crate) const MAGIC_87A: &[u8] = b"GIF87a";
 pub(crate) const OFFSET: usize = 0;
 
 use crate::{
@@ -105,7 +107,9 @@ pub fn write_tags(
     dest: &mut impl Write,
     tags: impl IntoIterator<Item = impl AsRef<str>>,
 ) -> Result<(), Error> {
-    passthrough(src, dest, MAGIC.len() as u64 + 4)?;
+    skip(src, MAGIC.len() as i64)?;
+    dest.write_all(MAGIC)?;
+    passthrough(src, dest, 4)?;
     let packed = read_byte(src)?;
     dest.write_all(&[packed])?;
     passthrough(src, dest, 2)?;
@@ -169,18 +173,28 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
-    const START: &[u8] = &[0u8; 13];
+    const START: &[u8] = b"GIF89a\x00\x00\x00\x00\x00\x00\x00";
     const END: &[u8] = &[0x3B];
     const TAGS: &[&[u8]] = &[&[0x21, 0xFF, 0xB], IDENTIFIER, &[0x01, 0x00, 0x00]];
     const DESCRIPTOR: &[&[u8]] = &[&[0x2C], &[0; 8], &[0x80], &[0; 8]];
 
     #[test]
     fn local_color_tables() {
-        let src = &[START, &DESCRIPTOR.concat(), &END].concat();
+        let src = &[START, &DESCRIPTOR.concat(), END].concat();
         assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
         let mut dest = Vec::new();
         write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
-        let expected = &[START, &TAGS.concat(), &DESCRIPTOR.concat(), &END].concat();
+        let expected = &[START, &TAGS.concat(), &DESCRIPTOR.concat(), END].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn upgrade_87a() {
+        let src = &[MAGIC_87A, &START[MAGIC.len()..], END].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let expected = &[START, &TAGS.concat(), END].concat();
         assert_eq!(&dest, expected);
     }
 }
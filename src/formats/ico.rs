@@ -0,0 +1,149 @@
+//! # Windows Icon
+//!
+//! ICO files, and CUR files for cursors, start with a 6 byte header, structured as follows:
+//!
+//! - 2 reserved bytes, which are always zero.
+//! - 2 byte little endian image type, which is 1 for icons and 2 for cursors.
+//! - 2 byte little endian number of images.
+//!
+//! This is followed by a directory with a 16 byte entry for each image, which describes its
+//! dimensions and colors, and ends with the 4 byte little endian size of the image data,
+//! `dwBytesInRes`, and its 4 byte little endian offset from the start of the file,
+//! `dwImageOffset`. The image data itself can be either a BMP without its file header, or a full
+//! PNG file.
+//!
+//! MemeDB stores its tags in a region right after the directory, prefixed by the `MemeDB\0`
+//! identifier. Since this pushes back all of the image data, every `dwImageOffset` gets shifted
+//! accordingly.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for ICO](https://en.wikipedia.org/wiki/ICO_(file_format))
+//! - [Icons documentation](https://learn.microsoft.com/en-us/previous-versions/ms997538(v=msdn.10))
+
+pub(crate) const MAGIC: &[u8] = b"\x00\x00\x01\x00";
+pub(crate) const CUR_MAGIC: &[u8] = b"\x00\x00\x02\x00";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, or_eof, read_heap, read_stack},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_ID: &[u8; 7] = b"MemeDB\x00";
+
+const HEADER_SIZE: u64 = 6;
+const ENTRY_SIZE: usize = 16;
+const IMAGE_OFFSET: usize = 12;
+
+fn image_offset(entry: &[u8]) -> u32 {
+    u32::from_le_bytes(entry[IMAGE_OFFSET..ENTRY_SIZE].try_into().unwrap())
+}
+
+/// Checks that the first directory entry is sane, since the magic number is too weak on its own.
+pub(crate) fn is_ico(_: &[u8], mut src: &mut dyn Read) -> Result<bool, std::io::Error> {
+    let Some(count) = or_eof(read_stack::<2>(&mut src))?.map(u16::from_le_bytes) else {
+        return Ok(false);
+    };
+    let Some(entry) = or_eof(read_stack::<ENTRY_SIZE>(&mut src))? else {
+        return Ok(false);
+    };
+    let directory_size = HEADER_SIZE + (count as usize * ENTRY_SIZE) as u64;
+    Ok(count > 0 && entry[3] == 0 && image_offset(&entry) as u64 >= directory_size)
+}
+
+/// Reads the header and directory, returning them along with the position right after them.
+fn read_directory(src: &mut (impl Read + Seek)) -> Result<(Vec<u8>, u64), Error> {
+    let header = read_stack::<{ HEADER_SIZE as usize }>(src)?;
+    let count = u16::from_le_bytes([header[4], header[5]]);
+    let mut directory = header.to_vec();
+    directory.extend(read_heap(src, count as usize * ENTRY_SIZE)?);
+    Ok((directory, src.stream_position()?))
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    read_directory(src)?;
+    match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => decode_tags(src),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let (mut directory, end) = read_directory(src)?;
+    let rest = match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => {
+            decode_tags(src)?;
+            src.stream_position()?
+        }
+        _ => end,
+    };
+
+    let mut tags_bytes = TAGS_ID.to_vec();
+    encode_tags(tags, &mut tags_bytes)?;
+
+    // Offsets are shifted to account for the old tags being replaced by the new ones
+    let old_size = (rest - end) as u32;
+    let new_size = u32::try_from(tags_bytes.len()).map_err(|_| Error::TagsTooLarge)?;
+    for entry in directory[HEADER_SIZE as usize..].chunks_exact_mut(ENTRY_SIZE) {
+        let offset = image_offset(entry);
+        if offset as u64 >= rest - start {
+            let offset = (offset - old_size).checked_add(new_size).ok_or(Error::TagsTooLarge)?;
+            entry[IMAGE_OFFSET..].copy_from_slice(&offset.to_le_bytes());
+        }
+    }
+
+    dest.write_all(&directory)?;
+    dest.write_all(&tags_bytes)?;
+    src.seek(SeekFrom::Start(rest))?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &[u8] = &[0, 0, 1, 0, 2, 0];
+    const ENTRY: &[u8] = &[1, 1, 0, 0, 1, 0, 32, 0, 4, 0, 0, 0];
+    const TAGS: &[&[u8]] = &[TAGS_ID, &[0x83, b'f', b'o', b'o', 0x00]];
+
+    #[test]
+    fn shifted_offsets() {
+        let src = &[HEADER, ENTRY, &[38, 0, 0, 0], ENTRY, &[42, 0, 0, 0], b"abcdefgh"].concat();
+        assert!(is_ico(&src[..4], &mut &src[4..]).unwrap());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let directory: &[&[u8]] = &[HEADER, ENTRY, &[50, 0, 0, 0], ENTRY, &[54, 0, 0, 0]];
+        let expected = &[directory, TAGS, &[b"abcdefgh"]].concat().concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+
+        let mut untagged = Vec::new();
+        write_tags(&mut Cursor::new(&dest), &mut untagged, [] as [&str; 0]).unwrap();
+        let directory: &[&[u8]] = &[HEADER, ENTRY, &[46, 0, 0, 0], ENTRY, &[50, 0, 0, 0]];
+        let expected = &[directory, &[TAGS_ID, &[0], b"abcdefgh"]].concat().concat();
+        assert_eq!(&untagged, expected);
+    }
+
+    #[test]
+    fn weak_magic() {
+        // An ISOBMFF file whose first box happens to be 256 bytes long
+        let src = &[&[0, 0, 1, 0][..], b"ftypisom", &[0; 12]].concat();
+        assert!(!is_ico(&src[..4], &mut &src[4..]).unwrap());
+    }
+}
+
+crate::utils::standard_tests!("ico");
+crate::utils::standard_tests!("cur", cur_standard_tests);
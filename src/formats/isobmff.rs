@@ -10,46 +10,78 @@
 //! - The box data itself, which may consist of other boxes.
 //!
 //! An ISOBMFF file consists of a series of boxes, the first of which must be of the type `ftyp`.
+//! JPEG 2000 files are an exception to this, since they start with a 12 byte signature box of the
+//! type `jP  `, which is then followed by the `ftyp` box. Classic QuickTime files may also lack an
+//! `ftyp` box entirely, starting directly with a `moov`, `mdat`, `wide`, `free` or `skip` box.
+//! Since those are rather weak magic numbers, the first few boxes are checked to make sure they are
+//! plausible before identifying the file as QuickTime, and padding boxes larger than 16 KiB aren't
+//! accepted in front of them.
+//!
+//! HEIF images, including AVIF, are identified by the brands listed in their `ftyp` box. Rather
+//! than being a stream, they are a collection of items, whose data is located through byte offsets
+//! stored in the `iloc` box inside the top level `meta` box.
 //!
 //! MemeDB stores its tags in a `uuid` box with the UUID `12EBC64DEA6247A08E92B9FB3B518C28`. The
-//! box is placed at the end of the file since boxes can reference data via byte offset.
+//! box is placed at the end of the file since boxes can reference data via byte offset. For the
+//! same reason, old tags found anywhere else are overwritten with a `free` box of the same size. If
+//! the last box lasts until the end of the file and is too large for a 32 bit size, its header has
+//! to grow, in which case the offsets in the `iloc` box of HEIF images are rewritten.
 //!
 //! ## Relevant Links
 //!
 //! - [Wikipedia article for ISOBMFF](https://en.wikipedia.org/wiki/ISO_base_media_file_format)
 //! - [ISO/IEC 14496-12 standard](https://www.iso.org/standard/83102.html)
+//! - [JPEG 2000 file format specification](https://www.itu.int/rec/T-REC-T.800)
+//! - [Wikipedia article for HEIF](https://en.wikipedia.org/wiki/High_Efficiency_Image_File_Format)
+//! - [ISO/IEC 23008-12 standard](https://www.iso.org/standard/83650.html)
+//! - [AVIF specification](https://aomediacodec.github.io/av1-avif/)
 
 pub(crate) const MAGIC: &[u8] = b"ftyp";
 pub(crate) const OFFSET: usize = 4;
+pub(crate) const JP2_MAGIC: &[u8] = b"\x00\x00\x00\x0CjP  \x0D\x0A\x87\x0A";
+pub(crate) const JP2_OFFSET: usize = 0;
+pub(crate) const QT_MAGIC: [&[u8]; 5] = [b"moov", b"mdat", b"wide", b"free", b"skip"];
 
 use crate::{
-    utils::{decode_tags, encode_tags, or_eof, passthrough, read_stack, skip},
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_exact_heap, read_stack},
     Error,
 };
-use std::io::{Read, Seek, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 const MEMEDB_UUID: [u8; 16] = *b"\x12\xeb\xc6\x4d\xea\x62\x47\xa0\x8e\x92\xb9\xfb\x3b\x51\x8c\x28";
 
+const FTYP: [u8; 4] = *b"ftyp";
+const FREE: [u8; 4] = *b"free";
+const META: [u8; 4] = *b"meta";
+const ILOC: [u8; 4] = *b"iloc";
+
+/// Largest padding box that may come before the `moov` or `mdat` box of a QuickTime file without
+/// an `ftyp` box, since detection has to keep every byte it reads.
+const MAX_PADDING_SIZE: u64 = 16 * 1024;
+
+const HEIF_BRANDS: [&[u8]; 10] =
+    [b"mif1", b"msf1", b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"avif", b"avis"];
+
 #[derive(Debug)]
 enum Size {
     Short(u32),
     Long(u64),
 }
 
-#[derive(Debug)]
-enum Type {
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Type {
     Short([u8; 4]),
     Long([u8; 16]),
 }
 
 #[derive(Debug)]
-struct Box {
+pub(crate) struct Box {
     size: Size,
     r#type: Type,
 }
 
 impl Box {
-    fn new(r#type: Type, data_size: u64) -> Self {
+    pub(crate) fn new(r#type: Type, data_size: u64) -> Self {
         let type_size = match r#type {
             Type::Short(_) => 4,
             Type::Long(_) => 4 + 16,
@@ -79,7 +111,7 @@ impl Box {
         Ok(r#box)
     }
 
-    fn write(&self, dest: &mut impl Write) -> Result<(), std::io::Error> {
+    pub(crate) fn write(&self, dest: &mut impl Write) -> Result<(), std::io::Error> {
         match self.size {
             Size::Short(s) => dest.write_all(&s.to_be_bytes())?,
             Size::Long(_) => dest.write_all(&[0, 0, 0, 1])?,
@@ -87,7 +119,7 @@ impl Box {
         match self.r#type {
             Type::Short(t) => dest.write_all(&t)?,
             Type::Long(_) => dest.write_all(b"uuid")?,
-        };
+        }
         if let Size::Long(s) = self.size {
             dest.write_all(&s.to_be_bytes())?;
         }
@@ -97,21 +129,70 @@ impl Box {
         Ok(())
     }
 
-    fn data_size(&self) -> u64 {
+    fn header_size(&self) -> u64 {
+        let size_size = match self.size {
+            Size::Short(_) => 4,
+            Size::Long(_) => 12,
+        };
         let type_size = match self.r#type {
             Type::Short(_) => 4,
             Type::Long(_) => 20,
         };
+        size_size + type_size
+    }
+
+    fn data_size(&self) -> u64 {
         // Prevents panic when box size is impossibly small, will instead silently pass through.
         match self.size {
-            Size::Short(s) => (s as u64).saturating_sub(4 + type_size),
-            Size::Long(s) => s.saturating_sub(12 + type_size),
+            Size::Short(s) => (s as u64).saturating_sub(self.header_size()),
+            Size::Long(s) => s.saturating_sub(self.header_size()),
         }
     }
 }
 
+fn is_tags_box(r#box: &Box) -> bool {
+    matches!(r#box, Box { size: Size::Short(1..) | Size::Long(_), r#type: Type::Long(MEMEDB_UUID) })
+}
+
+/// Checks that `header` and the boxes following it look like the start of a QuickTime file.
+pub(crate) fn is_quicktime(header: &[u8], mut src: &mut dyn Read) -> Result<bool, std::io::Error> {
+    let mut header: [u8; 8] = header.try_into().unwrap();
+    // Padding boxes can be skipped, but one of the two essential boxes should show up soon after
+    for _ in 0..4 {
+        let size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+        match &header[4..8] {
+            b"mdat" => return Ok(size == 0 || size == 1 || size >= 8),
+            b"moov" if size >= 16 => {
+                let Some(child) = or_eof(read_stack::<8>(&mut src))? else {
+                    return Ok(false);
+                };
+                let child_size = u32::from_be_bytes(child[0..4].try_into().unwrap()) as u64;
+                let child_type = &child[4..8];
+                let printable = child_type.iter().all(|b| b.is_ascii_alphanumeric() || *b == b' ');
+                return Ok((8..=size - 8).contains(&child_size) && printable);
+            }
+            b"wide" | b"free" | b"skip" | b"pnot" | b"PICT"
+                if (8..=MAX_PADDING_SIZE).contains(&size) =>
+            {
+                if passthrough(&mut src, &mut std::io::sink(), size - 8)? != size - 8 {
+                    return Ok(false);
+                }
+                match or_eof(read_stack::<8>(&mut src))? {
+                    Some(next) => header = next,
+                    None => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        }
+    }
+    Ok(false)
+}
+
 /// Given a `src`, return the tags contained inside.
 pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let start = src.stream_position()?;
+    let end = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
     while let Some(r#box) = or_eof(Box::read(src))? {
         if let Size::Short(0) = r#box.size {
             return Ok(Vec::new());
@@ -119,10 +200,11 @@ pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
         if let Type::Long(MEMEDB_UUID) = r#box.r#type {
             return decode_tags(src);
         }
-        let size = r#box.data_size();
-        // We passthrough instead of skip to get number of bytes read
-        if passthrough(src, &mut std::io::sink(), size)? != size {
-            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        // Seeking past the end is allowed, so the length has to be checked by hand
+        let position = src.stream_position()?;
+        match position.checked_add(r#box.data_size()) {
+            Some(next) if next <= end => src.seek(SeekFrom::Start(next))?,
+            _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
         };
     }
     Ok(Vec::new())
@@ -136,25 +218,81 @@ pub fn write_tags(
     dest: &mut impl Write,
     tags: impl IntoIterator<Item = impl AsRef<str>>,
 ) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let end = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+
+    // A first pass over the top level boxes finds out whether any data will have to move
+    let mut boxes = Vec::new();
+    let mut heif = false;
     while let Some(r#box) = or_eof(Box::read(src))? {
+        let position = src.stream_position()?;
         if let Size::Short(0) = r#box.size {
-            let pos = src.stream_position()?;
-            let len = src.seek(std::io::SeekFrom::End(0))?;
-            if pos != len {
-                src.seek(std::io::SeekFrom::Start(pos))?;
-            }
-            Box::new(r#box.r#type, len - pos).write(dest)?;
-            std::io::copy(src, dest)?;
+            boxes.push((position, r#box));
             break;
         }
-        if let Type::Long(MEMEDB_UUID) = r#box.r#type {
-            skip(src, r#box.data_size() as i64)?;
+        let next = match position.checked_add(r#box.data_size()) {
+            Some(next) if next <= end => next,
+            _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
+        };
+        if let Type::Short(FTYP) = r#box.r#type {
+            heif |= is_heif(&read_exact_heap(src, r#box.data_size())?);
+        }
+        boxes.push((position, r#box));
+        src.seek(SeekFrom::Start(next))?;
+    }
+    let last_kept = boxes.iter().rposition(|(_, r#box)| !is_tags_box(r#box));
+    // A box lasting until the end of the file needs an actual size, which may not fit in 32 bits
+    let shift = match boxes.last() {
+        Some((position, r#box @ Box { size: Size::Short(0), .. })) => {
+            let sized = Box::new(r#box.r#type, end - position);
+            Some((position - start, sized.header_size() - r#box.header_size()))
+                .filter(|(_, delta)| *delta > 0)
+        }
+        _ => None,
+    };
+    let map = |offset: u64| match shift {
+        Some((position, delta)) if offset >= position => offset.saturating_add(delta),
+        _ => offset,
+    };
+
+    for (i, (position, r#box)) in boxes.into_iter().enumerate() {
+        src.seek(SeekFrom::Start(position))?;
+        if is_tags_box(&r#box) {
+            // Old tags are overwritten in place unless they're at the very end, so nothing moves
+            if last_kept.is_some_and(|last| i < last) {
+                let size = r#box.header_size() + r#box.data_size();
+                let size = match u32::try_from(size) {
+                    Ok(size) => Size::Short(size),
+                    Err(_) => Size::Long(size),
+                };
+                let free = Box { size, r#type: Type::Short(FREE) };
+                free.write(dest)?;
+                let padding = free.data_size();
+                std::io::copy(&mut std::io::repeat(0).take(padding), dest)?;
+            }
+        } else if let Size::Short(0) = r#box.size {
+            Box::new(r#box.r#type, end - position).write(dest)?;
+            std::io::copy(src, dest)?;
+        } else if let (Type::Short(META), true, Some(_)) = (&r#box.r#type, heif, shift) {
+            let mut data = read_exact_heap(src, r#box.data_size())?;
+            rewrite_meta(&mut data, map)?;
+            r#box.write(dest)?;
+            dest.write_all(&data)?;
         } else {
             r#box.write(dest)?;
             passthrough(src, dest, r#box.data_size())?;
-        };
+        }
     }
 
+    write_tags_box(dest, tags)
+}
+
+/// Writes a box containing the provided `tags` to `dest`.
+pub(crate) fn write_tags_box(
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
     let mut tag_bytes = Vec::new();
     encode_tags(tags, &mut tag_bytes)?;
     let r#box = Box::new(Type::Long(MEMEDB_UUID), tag_bytes.len() as u64);
@@ -163,6 +301,95 @@ pub fn write_tags(
     Ok(())
 }
 
+/// Checks the brands of a `ftyp` box for any of the HEIF ones, which includes AVIF.
+fn is_heif(ftyp: &[u8]) -> bool {
+    // The second field is the minor version rather than a brand
+    let mut brands = ftyp.chunks_exact(4).enumerate().filter(|(i, _)| *i != 1);
+    brands.any(|(_, brand)| HEIF_BRANDS.contains(&brand))
+}
+
+/// Finds the `iloc` box within the data of a `meta` box, and rewrites the offsets inside.
+fn rewrite_meta(data: &mut [u8], map: impl Fn(u64) -> u64) -> Result<(), Error> {
+    // The `meta` box starts with a version and flags
+    let mut position = 4;
+    while position < data.len() {
+        let mut rest = &data[position..];
+        let r#box = Box::read(&mut rest)?;
+        let data_start = data.len() - rest.len();
+        let data_end = match data_start.checked_add(r#box.data_size() as usize) {
+            Some(data_end) if data_end <= data.len() => data_end,
+            _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
+        };
+        if let Type::Short(ILOC) = r#box.r#type {
+            return rewrite_iloc(&mut data[data_start..data_end], map);
+        }
+        if let Size::Short(0) = r#box.size {
+            break;
+        }
+        position = data_end;
+    }
+    Ok(())
+}
+
+/// Rewrites the file offsets of every item in the data of an `iloc` box.
+///
+/// Items stored in an `idat` box, in other items, or in other files are left untouched.
+fn rewrite_iloc(data: &mut [u8], map: impl Fn(u64) -> u64) -> Result<(), Error> {
+    let mut position = 0;
+    let version = read_uint(data, &mut position, 1)?;
+    position += 3;
+    let sizes = read_uint(data, &mut position, 2)?;
+    let offset_size = (sizes >> 12) as usize;
+    let length_size = (sizes >> 8 & 0xF) as usize;
+    let base_offset_size = (sizes >> 4 & 0xF) as usize;
+    let index_size = if version > 0 { (sizes & 0xF) as usize } else { 0 };
+    let id_size = if version < 2 { 2 } else { 4 };
+    let item_count = read_uint(data, &mut position, id_size)?;
+    for _ in 0..item_count {
+        position += id_size;
+        let construction_method = match version {
+            0 => 0,
+            _ => read_uint(data, &mut position, 2)? & 0xF,
+        };
+        let data_reference_index = read_uint(data, &mut position, 2)?;
+        let in_file = construction_method == 0 && data_reference_index == 0;
+        let base_position = position;
+        let base_offset = read_uint(data, &mut position, base_offset_size)?;
+        let new_base_offset = if in_file { map(base_offset) } else { base_offset };
+        write_uint(data, base_position, base_offset_size, new_base_offset)?;
+        let extent_count = read_uint(data, &mut position, 2)?;
+        for _ in 0..extent_count {
+            position += index_size;
+            let offset_position = position;
+            let offset = read_uint(data, &mut position, offset_size)?;
+            read_uint(data, &mut position, length_size)?;
+            if in_file && offset_size > 0 {
+                let new_offset = map(base_offset.saturating_add(offset)) - new_base_offset;
+                write_uint(data, offset_position, offset_size, new_offset)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a big-endian number of `size` bytes, which may be zero.
+fn read_uint(data: &[u8], position: &mut usize, size: usize) -> Result<u64, std::io::Error> {
+    let bytes = data.get(*position..*position + size);
+    let bytes = bytes.ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    *position += size;
+    Ok(bytes.iter().fold(0, |n, byte| n << 8 | *byte as u64))
+}
+
+/// Writes a big-endian number of `size` bytes, failing if it doesn't fit.
+fn write_uint(data: &mut [u8], position: usize, size: usize, n: u64) -> Result<(), Error> {
+    if size < 8 && n >> (size * 8) != 0 {
+        return Err(Error::TagsTooLarge);
+    }
+    let bytes = &n.to_be_bytes()[8 - size..];
+    data[position..position + size].copy_from_slice(bytes);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,6 +398,71 @@ mod tests {
     const ZERO_BOX: &[&[u8]] = &[&0u32.to_be_bytes(), &[0; 8]];
     const SIZED_BOX: &[&[u8]] = &[&12u32.to_be_bytes(), &[0; 8]];
     const TAGS: &[&[u8]] = &[&26u32.to_be_bytes(), b"uuid", &MEMEDB_UUID, &[0x80, 0x00]];
+    const HEIC_FTYP: &[&[u8]] = &[&20u32.to_be_bytes(), b"ftyp", b"heic", &[0; 4], b"mif1"];
+    // An `iloc` box with a single extent, pointing right after the 8 byte header of the `mdat`
+    const META: &[&[u8]] = &[
+        &42u32.to_be_bytes(),
+        b"meta",
+        &[0; 4],
+        &30u32.to_be_bytes(),
+        b"iloc",
+        &[0; 4],
+        &[0x44, 0x00, 0x00, 0x01],
+        &[0x00, 0x01, 0x00, 0x00, 0x00, 0x01],
+        &96u32.to_be_bytes(),
+        &4u32.to_be_bytes(),
+    ];
+    const MDAT: &[&[u8]] = &[&12u32.to_be_bytes(), b"mdat", b"item"];
+
+    /// Keeps track of how many bytes were actually read.
+    struct CountingReader<T> {
+        inner: T,
+        count: usize,
+    }
+
+    impl<T: Read> Read for CountingReader<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.count += n;
+            Ok(n)
+        }
+    }
+
+    impl<T: Seek> Seek for CountingReader<T> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn skip_large_box() {
+        let src = &[&0x100008u32.to_be_bytes(), b"mdat", &vec![0; 0x100000][..]].concat();
+        let mut src = CountingReader { inner: Cursor::new(src), count: 0 };
+        assert_eq!(read_tags(&mut src).unwrap(), Vec::<String>::new());
+        assert!(src.count < 0x100);
+    }
+
+    #[test]
+    fn truncated_box() {
+        let src = &[&0x100008u32.to_be_bytes(), b"mdat", &[0; 0x100][..]].concat();
+        assert!(matches!(read_tags(&mut Cursor::new(src)), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn quicktime_detection() {
+        const WIDE: &[&[u8]] = &[&8u32.to_be_bytes(), b"wide"];
+        const MDAT: &[&[u8]] = &[&16u32.to_be_bytes(), b"mdat", &[0; 8]];
+        const MOOV: &[&[u8]] = &[&16u32.to_be_bytes(), b"moov", &8u32.to_be_bytes(), b"mvhd"];
+        const GARBAGE: &[&[u8]] = &[&16u32.to_be_bytes(), b"moov", &[0xFF; 8]];
+        let is_quicktime = |src: &[u8]| is_quicktime(&src[..8], &mut &src[8..]).unwrap();
+        assert!(is_quicktime(&[WIDE, MDAT].concat().concat()));
+        assert!(is_quicktime(&MOOV.concat()));
+        assert!(!is_quicktime(&[WIDE, WIDE].concat().concat()));
+        assert!(!is_quicktime(&GARBAGE.concat()));
+        // Huge padding boxes are rejected without reading through them
+        let free = &[&u32::MAX.to_be_bytes()[..], b"free"].concat();
+        assert!(!super::is_quicktime(free, &mut std::io::repeat(0)).unwrap());
+    }
 
     #[test]
     fn size_zero_box() {
@@ -181,6 +473,56 @@ mod tests {
         let expected = &[SIZED_BOX.concat(), TAGS.concat()].concat();
         assert_eq!(&dest, expected);
     }
+
+    #[test]
+    fn heif_detection() {
+        assert!(is_heif(b"heic\0\0\0\0mif1"));
+        assert!(is_heif(b"MA1B\0\0\0\0avif"));
+        assert!(!is_heif(b"isomavifmp41"));
+    }
+
+    #[test]
+    fn heif_inner_tags() {
+        // Removing the tags would move the `mdat`, so they get overwritten with padding instead
+        let src = &[HEIC_FTYP, META, TAGS, MDAT].concat().concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, vec![""]).unwrap();
+        let free: &[&[u8]] = &[&26u32.to_be_bytes(), b"free", &[0; 18]];
+        let expected = &[HEIC_FTYP, META, free, MDAT, TAGS].concat().concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(&dest[96..100], b"item");
+    }
+
+    #[test]
+    fn iloc_offsets() {
+        let mut iloc = [
+            &[1, 0, 0, 0][..],
+            &[0x44, 0x40, 0x00, 0x02],
+            // An item stored in the file, with a base offset and an extent offset
+            &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+            &100u32.to_be_bytes(),
+            &[0x00, 0x01],
+            &10u32.to_be_bytes(),
+            &4u32.to_be_bytes(),
+            // An item stored in an `idat` box, which doesn't move
+            &[0x00, 0x02, 0x00, 0x01, 0x00, 0x00],
+            &100u32.to_be_bytes(),
+            &[0x00, 0x01],
+            &10u32.to_be_bytes(),
+            &4u32.to_be_bytes(),
+        ]
+        .concat();
+        let mut expected = iloc.clone();
+        expected[14..18].copy_from_slice(&108u32.to_be_bytes());
+        rewrite_iloc(&mut iloc, |offset| if offset >= 50 { offset + 8 } else { offset }).unwrap();
+        assert_eq!(iloc, expected);
+        let overflow = rewrite_iloc(&mut iloc, |offset| offset + u32::MAX as u64);
+        assert!(matches!(overflow, Err(Error::TagsTooLarge)));
+    }
 }
 
 crate::utils::standard_tests!("mp4");
+crate::utils::standard_tests!("jp2", jp2_standard_tests);
+crate::utils::standard_tests!("mov", mov_standard_tests);
+crate::utils::standard_tests!("heic", heic_standard_tests);
+crate::utils::standard_tests!("avif", avif_standard_tests);
@@ -17,7 +17,14 @@
 //! - On Exif files, the second marker segment must be `0xE1` with the id `Exif`.
 //! - The last marker must be `0xD9`.
 //!
-//! MemeDB stores its tags in a `0xE4` segment with the id `MemeDB`.
+//! MemeDB stores its tags in a `0xE4` segment with the id `MemeDB`, followed by a sequence number
+//! byte. Since a segment can hold less than 64 KiB of data, tags that don't fit in a single segment
+//! are split across up to 256 consecutive segments, numbered starting from 0.
+//!
+//! Some files carry more data after the `0xD9` marker, like the additional images of a
+//! Multi-Picture Format file or the video of a motion photo. That data is copied as-is, and the
+//! offsets in the MPF `0xE2` segment are adjusted to account for any segments that were added or
+//! removed.
 //!
 //! ## Relevant Links
 //!
@@ -26,6 +33,7 @@
 //! - [The JPEG specification](https://www.w3.org/Graphics/JPEG/itu-t81.pdf)
 //! - [The JFIF specification](https://www.w3.org/Graphics/JPEG/jfif3.pdf)
 //! - [A description of the Exif file format](https://www.media.mit.edu/pia/Research/deepview/exif.html)
+//! - [The Multi-Picture Format specification](https://www.cipa.jp/std/documents/e/DC-X007-KEY_E.pdf)
 
 pub(crate) const MAGIC: &[u8] = b"\xFF\xD8";
 pub(crate) const OFFSET: usize = 0;
@@ -34,11 +42,142 @@ use crate::{
     utils::{decode_tags, encode_tags, passthrough, read_byte, read_heap, read_stack, skip},
     Error,
 };
-use std::io::{BufRead, Read, Seek, Write};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+const TAGS_ID: &[u8] = b"MemeDB";
+const MAX_SEGMENT_SIZE: usize = u16::MAX as usize - 2 - TAGS_ID.len() - 1;
+const MAX_SEGMENTS: usize = u8::MAX as usize + 1;
+const MPF_ID: &[u8] = b"MPF\x00";
+const MP_ENTRY_TAG: u16 = 0xB002;
+
+/// Reads tags split across consecutive segments, only moving on to the next segment once the
+/// current one runs out.
+struct Segments<'a, T: Read> {
+    src: &'a mut T,
+    remaining: u64,
+    sequence: u8,
+}
+
+impl<T: Read> Read for Segments<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind::InvalidData};
+        while self.remaining == 0 {
+            let sequence = self.sequence.checked_add(1).ok_or(Error::from(InvalidData))?;
+            if read_stack::<2>(self.src)? != [0xFF, 0xE4] {
+                return Err(Error::from(InvalidData));
+            }
+            let length = u16::from_be_bytes(read_stack::<2>(self.src)?).saturating_sub(2);
+            if length < TAGS_ID.len() as u16 + 1
+                || read_heap(self.src, TAGS_ID.len() + 1)? != [TAGS_ID, &[sequence]].concat()
+            {
+                return Err(Error::from(InvalidData));
+            }
+            self.remaining = (length - TAGS_ID.len() as u16 - 1) as u64;
+            self.sequence = sequence;
+        }
+        let n = self.src.take(self.remaining).read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            return Err(Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn write_segments(
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<u64, Error> {
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+    let segments = tags_bytes.chunks(MAX_SEGMENT_SIZE);
+    let count = segments.len();
+    if count > MAX_SEGMENTS {
+        return Err(Error::TagsTooLarge);
+    }
+    for (sequence, segment) in segments.enumerate() {
+        dest.write_all(&[0xFF, 0xE4])?;
+        dest.write_all(&((2 + TAGS_ID.len() + 1 + segment.len()) as u16).to_be_bytes())?;
+        dest.write_all(TAGS_ID)?;
+        dest.write_all(&[sequence as u8])?;
+        dest.write_all(segment)?;
+    }
+    Ok((tags_bytes.len() + count * (4 + TAGS_ID.len() + 1)) as u64)
+}
+
+/// Walks the rest of the image in `src`, returning the total size of the tag segments found.
+fn tag_segments_size(src: &mut impl BufRead) -> Result<u64, Error> {
+    let mut size = 0;
+    let mut marker = read_marker(src)?;
+    loop {
+        match marker {
+            0xD9 => return Ok(size),
+            0x00 => return Err(Error::JpegInvalidMarker(marker)),
+            0x01 | 0xD0..=0xD8 => {}
+            0x02..=0xCF | 0xDA..=0xFE => {
+                let length = u16::from_be_bytes(read_stack::<2>(src)?).saturating_sub(2);
+                let segment = read_heap(src, length as usize)?;
+                if marker == 0xE4 && segment.len() > TAGS_ID.len() && segment.starts_with(TAGS_ID) {
+                    size += 4 + length as u64;
+                }
+            }
+            0xFF => unreachable!(),
+        }
+        marker = match marker {
+            0xD0..=0xD7 | 0xDA => passthrough_ecs(src, &mut std::io::sink())?,
+            _ => read_marker(src)?,
+        }
+    }
+}
+
+/// Adjusts the entries of the MP Index IFD in an MPF segment. The size of the first image is
+/// shifted by `size_delta`, and the offsets of all other images by `offset_delta`.
+///
+/// Malformed segments are left as they are.
+fn adjust_mpf(tiff: &mut [u8], size_delta: i64, offset_delta: i64) -> Option<()> {
+    let little_endian = match tiff.get(0..4)? {
+        b"II*\x00" => true,
+        b"MM\x00*" => false,
+        _ => return None,
+    };
+    let read_u16 = |tiff: &[u8], i: usize| {
+        let bytes = tiff.get(i..i.checked_add(2)?)?.try_into().ok()?;
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let read_u32 = |tiff: &[u8], i: usize| {
+        let bytes = tiff.get(i..i.checked_add(4)?)?.try_into().ok()?;
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+    let shift = |n: u32, delta: i64| -> Option<[u8; 4]> {
+        let n = u32::try_from(n as i64 + delta).ok()?;
+        Some(if little_endian { n.to_le_bytes() } else { n.to_be_bytes() })
+    };
 
-const TAGS_ID: &[u8] = b"MemeDB\x00";
+    let ifd = read_u32(tiff, 4)? as usize;
+    let entry = (0..read_u16(tiff, ifd)? as usize)
+        .map(|i| ifd + 2 + 12 * i)
+        .find(|&entry| read_u16(tiff, entry) == Some(MP_ENTRY_TAG))?;
+    let entries_size = read_u32(tiff, entry + 4)? as usize;
+    let entries_start = read_u32(tiff, entry + 8)? as usize;
+    let entries = tiff.get_mut(entries_start..entries_start.checked_add(entries_size)?)?;
+
+    let mut adjusted = Vec::new();
+    for entry in entries.chunks_exact(16) {
+        let size = read_u32(entry, 4)?;
+        let offset = read_u32(entry, 8)?;
+        if offset == 0 {
+            adjusted.push((4, shift(size, size_delta)?));
+        } else {
+            adjusted.push((8, shift(offset, offset_delta)?));
+        }
+    }
+    for (entry, (i, bytes)) in entries.chunks_exact_mut(16).zip(adjusted) {
+        entry[i..i + 4].copy_from_slice(&bytes);
+    }
+    Some(())
+}
 
-fn passthrough_ecs(src: &mut (impl Read + BufRead), dest: &mut impl Write) -> Result<u8, Error> {
+fn passthrough_ecs(src: &mut impl BufRead, dest: &mut impl Write) -> Result<u8, Error> {
     loop {
         let buf = src.fill_buf()?;
         let len = buf.len();
@@ -73,25 +212,26 @@ fn read_marker(src: &mut impl Read) -> Result<u8, Error> {
     }
     loop {
         match read_byte(src)? {
-            0xFF => continue,
+            0xFF => {}
             byte => return Ok(byte),
         }
     }
 }
 
 /// Given a `src`, return the tags contained inside.
-pub fn read_tags(src: &mut (impl Read + BufRead + Seek)) -> Result<Vec<String>, Error> {
+pub fn read_tags(src: &mut (impl BufRead + Seek)) -> Result<Vec<String>, Error> {
     let mut marker = read_marker(src)?;
     loop {
         match marker {
             0xE4 => {
                 let length = u16::from_be_bytes(read_stack::<2>(src)?).saturating_sub(2);
-                if length < TAGS_ID.len() as u16 {
+                if length < TAGS_ID.len() as u16 + 1 {
                     skip(src, length as i64)?;
-                } else if read_heap(src, TAGS_ID.len())? != TAGS_ID {
-                    skip(src, length.saturating_sub(TAGS_ID.len() as u16) as i64)?;
+                } else if read_heap(src, TAGS_ID.len() + 1)? != [TAGS_ID, &[0]].concat() {
+                    skip(src, length.saturating_sub(TAGS_ID.len() as u16 + 1) as i64)?;
                 } else {
-                    return decode_tags(src);
+                    let remaining = (length - TAGS_ID.len() as u16 - 1) as u64;
+                    return decode_tags(&mut Segments { src, remaining, sequence: 0 });
                 }
             }
             0xD9 => return Ok(Vec::new()),
@@ -115,46 +255,62 @@ pub fn read_tags(src: &mut (impl Read + BufRead + Seek)) -> Result<Vec<String>,
 ///
 /// This function will remove any tags that previously existed in `src`.
 pub fn write_tags(
-    src: &mut (impl Read + BufRead + Seek),
+    src: &mut (impl BufRead + Seek),
     dest: &mut impl Write,
     tags: impl IntoIterator<Item = impl AsRef<str>>,
 ) -> Result<(), Error> {
     passthrough(src, dest, 2)?; // Assume SOI marker
     let mut tags = Some(tags);
+    let mut added = 0;
+    let mut removed = 0;
     let mut marker = read_marker(src)?;
     loop {
         if !matches!(marker, 0xE0 | 0xE1) {
             if let Some(tags) = tags.take() {
-                let mut tags_bytes = Vec::new();
-                encode_tags(tags, &mut tags_bytes)?;
-                dest.write_all(&[0xFF, 0xE4])?;
-                dest.write_all(&((2 + TAGS_ID.len() + tags_bytes.len()) as u16).to_be_bytes())?;
-                dest.write_all(TAGS_ID)?;
-                dest.write_all(&tags_bytes)?;
+                added = write_segments(dest, tags)?;
             }
         }
         match marker {
+            0xE2 => {
+                let length_bytes = read_stack::<2>(src)?;
+                let length = u16::from_be_bytes(length_bytes).saturating_sub(2);
+                let mut segment = read_heap(src, length as usize)?;
+                if segment.starts_with(MPF_ID) {
+                    // Offsets are relative to the MPF segment, so only segments after it move them
+                    let position = src.stream_position()?;
+                    let removed_after = tag_segments_size(src)?;
+                    src.seek(SeekFrom::Start(position))?;
+                    let size_delta = added as i64 - (removed + removed_after) as i64;
+                    adjust_mpf(&mut segment[MPF_ID.len()..], size_delta, -(removed_after as i64));
+                }
+                dest.write_all(&[0xFF, marker])?;
+                dest.write_all(&length_bytes)?;
+                dest.write_all(&segment)?;
+            }
             0xE4 => {
                 let length_bytes = read_stack::<2>(src)?;
                 let length = u16::from_be_bytes(length_bytes).saturating_sub(2);
-                if length < TAGS_ID.len() as u16 {
+                if length < TAGS_ID.len() as u16 + 1 {
                     dest.write_all(&[0xFF, marker])?;
                     dest.write_all(&length_bytes)?;
                     passthrough(src, dest, length as u64)?;
                 } else {
-                    let tag = read_heap(src, TAGS_ID.len())?;
-                    if tag == TAGS_ID {
-                        skip(src, length.saturating_sub(TAGS_ID.len() as u16) as i64)?;
+                    let tag = read_heap(src, TAGS_ID.len() + 1)?;
+                    let length = length.saturating_sub(TAGS_ID.len() as u16 + 1);
+                    if tag[..TAGS_ID.len()] == *TAGS_ID {
+                        skip(src, length as i64)?;
+                        removed += 4 + tag.len() as u64 + length as u64;
                     } else {
                         dest.write_all(&[0xFF, marker])?;
                         dest.write_all(&length_bytes)?;
                         dest.write_all(&tag)?;
-                        passthrough(src, dest, length.saturating_sub(TAGS_ID.len() as u16) as u64)?;
+                        passthrough(src, dest, length as u64)?;
                     }
                 }
             }
             0xD9 => {
                 dest.write_all(&[0xFF, marker])?;
+                std::io::copy(src, dest)?;
                 return Ok(());
             }
 
@@ -183,7 +339,7 @@ mod tests {
 
     const START: &[u8] = &[0xFF, 0xD8];
     const END: &[u8] = &[0xFF, 0xD9];
-    const TAGS: &[&[u8]] = &[&[0xFF, 0xE4, 0x00, 0x0A], TAGS_ID, &[0x00]];
+    const TAGS: &[&[u8]] = &[&[0xFF, 0xE4, 0x00, 0x0A], TAGS_ID, &[0x00], &[0x00]];
     const SMALL: &[u8] = &[0xFF, 0xE4, 0x00, 0x03, 0x00];
     const WRONG: &[&[u8]] = &[&[0xFF, 0xE4, 0x00, 0x09], &[0; 7]];
 
@@ -206,6 +362,48 @@ mod tests {
         let expected = &[START, &TAGS.concat(), &WRONG.concat(), END].concat();
         assert_eq!(&dest, expected);
     }
+
+    fn mpf(first_size: u32, second_offset: u32) -> Vec<u8> {
+        let ifd: &[&[u8]] =
+            &[&[0x00, 0x01, 0xB0, 0x02, 0x00, 0x07], &32u32.to_be_bytes(), &26u32.to_be_bytes()];
+        let first: &[&[u8]] = &[&[0; 4], &first_size.to_be_bytes(), &[0; 8]];
+        let second: &[&[u8]] = &[&[0; 4], &4u32.to_be_bytes(), &second_offset.to_be_bytes()];
+        let tiff: &[&[u8]] = &[b"MM\x00*", &8u32.to_be_bytes(), &ifd.concat(), &[0; 4]];
+        let entries: &[&[u8]] = &[&first.concat(), &second.concat(), &[0; 4]];
+        [&[0xFF, 0xE2, 0x00, 0x40], MPF_ID, &tiff.concat(), &entries.concat()].concat()
+    }
+
+    #[test]
+    fn trailing_data() {
+        let trailer: &[u8] = &[START, END].concat();
+        let src = &[START, &mpf(82, 72), &TAGS.concat(), END, trailer].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let expected = &[START, &TAGS.concat(), &mpf(82, 60), END, trailer].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn split_segments() {
+        let src = &[START, END].concat();
+        let tags = ["a".repeat(2 * MAX_SEGMENT_SIZE)];
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, &tags).unwrap();
+        assert_eq!(dest.windows(TAGS_ID.len()).filter(|w| w == &TAGS_ID).count(), 3);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), tags);
+        let mut rewritten = Vec::new();
+        write_tags(&mut Cursor::new(&dest), &mut rewritten, &tags).unwrap();
+        assert_eq!(rewritten, dest);
+    }
+
+    #[test]
+    fn too_large() {
+        let src = &[START, END].concat();
+        let tags = ["a".repeat(MAX_SEGMENTS * MAX_SEGMENT_SIZE)];
+        let result = write_tags(&mut Cursor::new(src), &mut std::io::sink(), &tags);
+        assert!(matches!(result, Err(Error::TagsTooLarge)));
+    }
 }
 
 crate::utils::standard_tests!("jpeg");
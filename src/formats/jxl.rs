@@ -0,0 +1,102 @@
+//! # JPEG XL
+//!
+//! JPEG XL images come in two forms. The simplest one is a bare codestream, which starts with the
+//! `FF 0A` marker and contains nothing but the image itself.
+//!
+//! The other one is a container, which is structured like an [ISOBMFF][crate::isobmff] file. It
+//! starts with a 12 byte signature box of the type `JXL `, followed by a `ftyp` box with the brand
+//! `jxl `. The codestream is then stored either in a single `jxlc` box, or split across several
+//! `jxlp` boxes, with metadata such as Exif or XMP stored in boxes of their own.
+//!
+//! MemeDB stores its tags in the same `uuid` box used for ISOBMFF files, placed at the end of the
+//! container. Since a bare codestream has nowhere to put them, it gets wrapped into a container
+//! with a `jxlc` box when writing tags.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for JPEG XL](https://en.wikipedia.org/wiki/JPEG_XL)
+//! - [ISO/IEC 18181-2 standard](https://www.iso.org/standard/85253.html)
+//! - [JPEG XL container overview](https://github.com/libjxl/libjxl/blob/main/doc/format_overview.md)
+
+pub(crate) const MAGIC: &[u8] = b"\x00\x00\x00\x0CJXL \x0D\x0A\x87\x0A";
+pub(crate) const OFFSET: usize = 0;
+pub(crate) const CODESTREAM_MAGIC: &[u8] = b"\xFF\x0A";
+
+use crate::{
+    isobmff::{self, Box, Type},
+    utils::read_stack,
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// The `ftyp` box data of a JPEG XL container.
+const FTYP: &[u8] = b"jxl \x00\x00\x00\x00jxl ";
+
+/// Checks whether `src` is a bare codestream, leaving it where it started.
+fn is_codestream(src: &mut (impl Read + Seek)) -> Result<bool, std::io::Error> {
+    let start = src.stream_position()?;
+    let codestream = read_stack::<2>(src)? == CODESTREAM_MAGIC;
+    src.seek(SeekFrom::Start(start))?;
+    Ok(codestream)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    if is_codestream(src)? {
+        return Ok(Vec::new());
+    }
+    isobmff::read_tags(src)
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    if !is_codestream(src)? {
+        return isobmff::write_tags(src, dest, tags);
+    }
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))? - start;
+    src.seek(SeekFrom::Start(start))?;
+    dest.write_all(MAGIC)?;
+    Box::new(Type::Short(*b"ftyp"), FTYP.len() as u64).write(dest)?;
+    dest.write_all(FTYP)?;
+    Box::new(Type::Short(*b"jxlc"), len).write(dest)?;
+    std::io::copy(src, dest)?;
+    isobmff::write_tags_box(dest, tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const CODESTREAM: &[u8] = &[0xFF, 0x0A, 0xFA, 0x7F];
+    const FTYP_BOX: &[&[u8]] = &[&20u32.to_be_bytes(), b"ftyp", FTYP];
+    const JXLC: &[&[u8]] = &[&12u32.to_be_bytes(), b"jxlc", CODESTREAM];
+
+    #[test]
+    fn wrap_codestream() {
+        assert_eq!(read_tags(&mut Cursor::new(CODESTREAM)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(CODESTREAM), &mut dest, ["foo"]).unwrap();
+        assert_eq!(&dest[..44], &[&[MAGIC], FTYP_BOX, JXLC].concat().concat());
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn partial_codestream() {
+        let jxlp: &[&[u8]] = &[&16u32.to_be_bytes(), b"jxlp", &[0x80, 0, 0, 0], CODESTREAM];
+        let src = &[&[MAGIC], FTYP_BOX, jxlp].concat().concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        assert!(dest.starts_with(src));
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+}
+
+crate::utils::standard_tests!("jxl");
@@ -0,0 +1,285 @@
+//! # Matroska
+//!
+//! Matroska data is stored in EBML (Extensible Binary Meta Language) elements. Each element is
+//! structured as follows:
+//!
+//! - A variable length integer identifying the element, between 1 and 4 bytes long.
+//! - A variable length integer describing the length of the data within, between 1 and 8 bytes
+//!   long. If all of its value bits are set, the size is unknown and the element lasts until an
+//!   element that can't be its child is found.
+//! - The element data itself, which may consist of other elements.
+//!
+//! The length of a variable length integer is given by the amount of leading zeros in its first
+//! byte, and the marker bit that follows them is not part of the value.
+//!
+//! A Matroska file starts with an `EBML` header element, followed by a `Segment` element that
+//! contains everything else. Elements like `SeekHead` and `Cues` reference other elements by their
+//! offset from the start of the `Segment` data, so nothing can be moved inside it. WebM files use
+//! the same structure with a restricted set of codecs.
+//!
+//! MemeDB stores its tags in a private `0x1D6D656D` element at the end of the `Segment`. If an
+//! older one is found anywhere else, it is overwritten with a `Void` element of the same size.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for Matroska](https://en.wikipedia.org/wiki/Matroska)
+//! - [RFC 8794, the EBML specification](https://www.rfc-editor.org/rfc/rfc8794)
+//! - [RFC 9559, the Matroska specification](https://www.rfc-editor.org/rfc/rfc9559)
+//! - [WebM Container Guidelines](https://www.webmproject.org/docs/container/)
+
+pub(crate) const MAGIC: &[u8] = b"\x1A\x45\xDF\xA3";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_byte, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SEGMENT_ID: u32 = 0x18538067;
+const VOID_ID: u32 = 0xEC;
+const TAGS_ID: u32 = 0x1D6D656D;
+
+#[derive(Debug)]
+struct Element {
+    id: u32,
+    size: Option<u64>,
+    header: [u8; 12],
+    id_len: usize,
+    size_len: usize,
+}
+
+impl Element {
+    fn new(id: u32, size: u64) -> Option<Self> {
+        let id_len = 4 - id.leading_zeros() as usize / 8;
+        let size_len = (1..=8).find(|&w| size < (1 << (7 * w)) - 1)?;
+        let mut header = [0; 12];
+        header[..id_len].copy_from_slice(&id.to_be_bytes()[4 - id_len..]);
+        header[id_len..id_len + size_len].copy_from_slice(&vint(size, size_len));
+        Some(Self { id, size: Some(size), header, id_len, size_len })
+    }
+
+    fn read(src: &mut impl Read) -> Result<Option<Self>, Error> {
+        let Some(first) = or_eof(read_byte(src))? else {
+            return Ok(None);
+        };
+        let mut header = [0; 12];
+        let id_len = read_vint(src, first, &mut header, 4)?;
+        let first = read_byte(src)?;
+        let size_len = read_vint(src, first, &mut header[id_len..], 8)?;
+        let id = header[..id_len].iter().fold(0, |n, &b| n << 8 | b as u32);
+        let size_bytes = &header[id_len..id_len + size_len];
+        let marker = 1u64 << (8 - size_len);
+        let first = size_bytes[0] as u64 & (marker - 1);
+        let size = size_bytes[1..].iter().fold(first, |n, &b| n << 8 | b as u64);
+        let size = (size != (1 << (7 * size_len)) - 1).then_some(size);
+        Ok(Some(Self { id, size, header, id_len, size_len }))
+    }
+
+    fn header(&self) -> &[u8] {
+        &self.header[..self.id_len + self.size_len]
+    }
+}
+
+/// Reads the rest of a variable length integer starting with `first` into `buf`, returning its
+/// length.
+fn read_vint(src: &mut impl Read, first: u8, buf: &mut [u8], max: usize) -> Result<usize, Error> {
+    let len = first.leading_zeros() as usize + 1;
+    if len > max {
+        return Err(Error::MatroskaInvalidVint(first));
+    }
+    buf[0] = first;
+    src.read_exact(&mut buf[1..len])?;
+    Ok(len)
+}
+
+fn vint(n: u64, len: usize) -> Vec<u8> {
+    (n | 1 << (7 * len)).to_be_bytes()[8 - len..].to_vec()
+}
+
+/// Finds the `Segment` element, passing through everything before it to `dest`.
+fn find_segment(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+) -> Result<Option<Element>, Error> {
+    while let Some(element) = Element::read(src)? {
+        if element.id == SEGMENT_ID {
+            return Ok(Some(element));
+        }
+        dest.write_all(element.header())?;
+        // Elements of unknown size can only be walked through by reading their children
+        if let Some(size) = element.size {
+            if passthrough(src, dest, size)? != size {
+                Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+    let Some(segment) = find_segment(src, &mut std::io::sink())? else {
+        return Ok(Vec::new());
+    };
+    let segment_end = match segment.size {
+        Some(size) => src.stream_position()?.saturating_add(size).min(len),
+        None => len,
+    };
+    while src.stream_position()? < segment_end {
+        let Some(element) = Element::read(src)? else {
+            break;
+        };
+        match (element.id, element.size) {
+            (_, None) => {}
+            (TAGS_ID, Some(size)) => return decode_tags(&mut src.take(size)),
+            (_, Some(size)) => {
+                // Seeking past the end is allowed, so the length has to be checked by hand
+                match src.stream_position()?.checked_add(size) {
+                    Some(next) if next <= len => src.seek(SeekFrom::Start(next))?,
+                    _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
+                };
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+    let Some(segment) = find_segment(src, dest)? else {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    };
+    let data_start = src.stream_position()?;
+    let segment_end = match segment.size {
+        Some(size) => data_start.checked_add(size).filter(|&end| end <= len),
+        None => Some(len),
+    };
+    let segment_end = segment_end.ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+
+    // A first pass finds out if the segment already ends with our tags, so they can be dropped
+    let mut trailing_tags = None;
+    while src.stream_position()? < segment_end {
+        let position = src.stream_position()?;
+        let Some(element) = Element::read(src)? else {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        };
+        trailing_tags = None;
+        if let Some(size) = element.size {
+            match src.stream_position()?.checked_add(size) {
+                Some(next) if next <= segment_end => src.seek(SeekFrom::Start(next))?,
+                _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
+            };
+            if element.id == TAGS_ID {
+                trailing_tags = Some(position);
+            }
+        }
+    }
+    let cut = trailing_tags.unwrap_or(segment_end);
+
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+    let tags_element = Element::new(TAGS_ID, tags_bytes.len() as u64).ok_or(Error::TagsTooLarge)?;
+
+    dest.write_all(&segment.header[..segment.id_len])?;
+    if segment.size.is_some() {
+        let size = cut - data_start + tags_element.header().len() as u64 + tags_bytes.len() as u64;
+        // Keep the original size length if possible, since most muxers reserve 8 bytes for it
+        let size_len = match segment.size_len {
+            n if size < (1 << (7 * n)) - 1 => n,
+            _ if size < (1 << 56) - 1 => 8,
+            _ => return Err(Error::TagsTooLarge),
+        };
+        dest.write_all(&vint(size, size_len))?;
+    } else {
+        dest.write_all(&segment.header()[segment.id_len..])?;
+    }
+
+    // Anything inside the segment may be referenced by offset, so older tags are voided instead
+    src.seek(SeekFrom::Start(data_start))?;
+    while src.stream_position()? < cut {
+        let Some(element) = Element::read(src)? else {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        };
+        match (element.id, element.size) {
+            (_, None) => dest.write_all(element.header())?,
+            (TAGS_ID, Some(size)) => {
+                // The size of the void may need a longer length than necessary to fill the gap
+                let total = element.header().len() as u64 + size;
+                let (size_len, void_size) = (1..=8)
+                    .map(|n| (n, total - 1 - n as u64))
+                    .find(|&(n, s)| s < (1 << (7 * n)) - 1)
+                    .unwrap();
+                dest.write_all(&[VOID_ID as u8])?;
+                dest.write_all(&vint(void_size, size_len))?;
+                std::io::copy(&mut std::io::repeat(0).take(void_size), dest)?;
+                skip(src, size as i64)?;
+            }
+            (_, Some(size)) => {
+                dest.write_all(element.header())?;
+                passthrough(src, dest, size)?;
+            }
+        }
+    }
+    dest.write_all(tags_element.header())?;
+    dest.write_all(&tags_bytes)?;
+
+    src.seek(SeekFrom::Start(segment_end))?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &[u8] = &[0x1A, 0x45, 0xDF, 0xA3, 0x80];
+    const INFO: &[u8] = &[0x15, 0x49, 0xA9, 0x66, 0x82, 0x00, 0x00];
+    const TAGS: &[u8] = &[0x1D, 0x6D, 0x65, 0x6D, 0x85, 0x83, b'f', b'o', b'o', 0x00];
+    const EMPTY_TAGS: &[u8] = &[0x1D, 0x6D, 0x65, 0x6D, 0x81, 0x00];
+    const VOID: &[u8] = &[0xEC, 0x88, 0, 0, 0, 0, 0, 0, 0, 0];
+    const SEGMENT: &[u8] = &[0x18, 0x53, 0x80, 0x67];
+
+    #[test]
+    fn void_inner_tags() {
+        let src = &[HEADER, SEGMENT, &[0x91], TAGS, INFO].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), ["foo"]);
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, [] as [&str; 0]).unwrap();
+        let expected = &[HEADER, SEGMENT, &[0x97], VOID, INFO, EMPTY_TAGS].concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_size() {
+        let cluster: &[u8] = &[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x81, 0x00];
+        let src = &[HEADER, SEGMENT, &[0xFF], INFO, cluster, TAGS].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), ["foo"]);
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, [] as [&str; 0]).unwrap();
+        let expected = &[HEADER, SEGMENT, &[0xFF], INFO, cluster, EMPTY_TAGS].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn invalid_vint() {
+        let src = &[HEADER, &[0x00, 0x80]].concat();
+        assert!(matches!(read_tags(&mut Cursor::new(src)), Err(Error::MatroskaInvalidVint(0))));
+    }
+}
+
+crate::utils::standard_tests!("webm");
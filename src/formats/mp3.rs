@@ -0,0 +1,326 @@
+//! # MPEG-1 Audio Layer III
+//!
+//! MP3 files are a series of MPEG audio frames, each starting with a 4 byte header that begins
+//! with 11 set sync bits. Since frames don't have any room for metadata, it is usually stored in
+//! an ID3v2 tag before the first frame.
+//!
+//! An ID3v2 tag starts with a 10 byte header made of:
+//!
+//! - The `ID3` magic number.
+//! - 1 byte major version and 1 byte revision.
+//! - 1 byte of flags, marking if the tag is unsynchronised, has an extended header, or a footer.
+//! - 4 byte syncsafe number describing the length of the tag, excluding its header and footer.
+//!
+//! Syncsafe numbers only use the lower 7 bits of each byte, so they never contain sync bits. For
+//! the same reason, unsynchronisation inserts a zero byte after every `0xFF` byte in the tag.
+//!
+//! The header is followed by an optional extended header, and then a series of frames, each
+//! with a 4 byte ASCII identifier, a 4 byte length (syncsafe in ID3v2.4), and 2 bytes of flags.
+//! After the last frame, the rest of the tag may be filled with zeros as padding.
+//!
+//! MemeDB stores its tags in a `PRIV` frame with the owner identifier `MemeDB`. ID3v2.3 and
+//! ID3v2.4 tags are supported, and their padding is used to keep the size of the tag the same
+//! whenever possible. The extended header and footer are removed, since the extended header may
+//! contain a checksum of the frames. Files without an ID3v2 tag get a new ID3v2.3 tag. Tags of
+//! other versions can't hold MemeDB tags, so they are read as having none, but can't be written.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for MP3](https://en.wikipedia.org/wiki/MP3)
+//! - [Wikipedia article for ID3](https://en.wikipedia.org/wiki/ID3)
+//! - [ID3v2.3 specification](https://id3.org/id3v2.3.0)
+//! - [ID3v2.4 structure specification](https://id3.org/id3v2.4.0-structure)
+//! - [MPEG audio frame header](http://www.mp3-tech.org/programmer/frame_header.html)
+
+pub(crate) const MAGIC: &[u8] = b"ID3";
+pub(crate) const OFFSET: usize = 0;
+pub(crate) const MPEG_MAGIC: [&[u8]; 6] =
+    [b"\xFF\xFB", b"\xFF\xFA", b"\xFF\xF3", b"\xFF\xF2", b"\xFF\xE3", b"\xFF\xE2"];
+
+use crate::{
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_exact_heap, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_FRAME: &[u8] = b"PRIV";
+const TAGS_OWNER: &[u8] = b"MemeDB\0";
+const NEW_VERSION: u8 = 3;
+
+const UNSYNCHRONISATION: u8 = 0x80;
+const EXTENDED_HEADER: u8 = 0x40;
+const FOOTER: u8 = 0x10;
+
+const MAX_SYNCSAFE: usize = 0x0FFFFFFF;
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes[..4].iter().fold(0, |n, &b| n << 7 | (b & 0x7F) as u32)
+}
+
+fn to_syncsafe(n: u32) -> [u8; 4] {
+    [(n >> 21) as u8 & 0x7F, (n >> 14) as u8 & 0x7F, (n >> 7) as u8 & 0x7F, n as u8 & 0x7F]
+}
+
+/// Removes the zero bytes inserted after every `0xFF` byte by unsynchronisation.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut resynchronised = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &byte in data {
+        if !(previous == 0xFF && byte == 0x00) {
+            resynchronised.push(byte);
+        }
+        previous = byte;
+    }
+    resynchronised
+}
+
+/// Returns the size of an MPEG audio frame given its header, if the header is valid.
+fn frame_size(header: [u8; 4]) -> Option<u64> {
+    const MPEG_1_BITRATES: [u64; 15] =
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG_2_BITRATES: [u64; 15] =
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u64; 3] = [44100, 48000, 32000];
+    let sample_rate = *SAMPLE_RATES.get((header[2] >> 2 & 0b11) as usize)?;
+    let padding = (header[2] >> 1 & 1) as u64;
+    let bitrate = (header[2] >> 4) as usize;
+    let (bitrate, sample_rate, samples) = match header[1] >> 3 & 0b11 {
+        0b11 => (MPEG_1_BITRATES.get(bitrate)?, sample_rate, 144),
+        0b10 => (MPEG_2_BITRATES.get(bitrate)?, sample_rate / 2, 72),
+        0b00 => (MPEG_2_BITRATES.get(bitrate)?, sample_rate / 4, 72),
+        _ => return None,
+    };
+    // Free format streams don't describe their bitrate, so their frames can't be found
+    (*bitrate != 0).then(|| samples * bitrate * 1000 / sample_rate + padding)
+}
+
+/// Checks that `header` and the data following it look like the start of an MPEG audio stream.
+pub(crate) fn is_mpeg(header: &[u8], mut src: &mut dyn Read) -> Result<bool, std::io::Error> {
+    let Some(rest) = or_eof(read_stack::<2>(&mut src))? else {
+        return Ok(false);
+    };
+    let Some(size) = frame_size([header[0], header[1], rest[0], rest[1]]) else {
+        return Ok(false);
+    };
+    // A single frame header is too weak of a signal, so the next one has to be checked as well
+    if passthrough(&mut src, &mut std::io::sink(), size - 4)? != size - 4 {
+        return Ok(false);
+    }
+    match or_eof(read_stack::<2>(&mut src))? {
+        Some(next) => Ok(next[0] == 0xFF && next[1] & 0xFE == header[1] & 0xFE),
+        None => Ok(false),
+    }
+}
+
+struct Tag {
+    version: u8,
+    revision: u8,
+    size: usize,
+    frames: Vec<u8>,
+}
+
+/// Reads the ID3v2 tag at the start of `src`, if there is one. Otherwise `src` is left untouched.
+fn read_tag(src: &mut (impl Read + Seek)) -> Result<Option<Tag>, Error> {
+    let start = src.stream_position()?;
+    let header = match or_eof(read_stack::<10>(src))? {
+        Some(header) if header.starts_with(MAGIC) => header,
+        _ => {
+            src.seek(SeekFrom::Start(start))?;
+            return Ok(None);
+        }
+    };
+    let (version, revision, flags) = (header[3], header[4], header[5]);
+    if !(3..=4).contains(&version) {
+        return Err(Error::Mp3UnsupportedVersion(version));
+    }
+    let size = syncsafe(&header[6..]) as usize;
+    let mut frames = read_exact_heap(src, size as u64)?;
+    if flags & FOOTER != 0 {
+        skip(src, 10)?;
+    }
+    // In ID3v2.4 unsynchronisation is done for each frame instead
+    if version == 3 && flags & UNSYNCHRONISATION != 0 {
+        frames = resynchronise(&frames);
+    }
+    if flags & EXTENDED_HEADER != 0 {
+        let extended_size = match version {
+            3 => u32::from_be_bytes(read_stack::<4>(&mut &frames[..])?) as usize + 4,
+            _ => syncsafe(&read_stack::<4>(&mut &frames[..])?) as usize,
+        };
+        if extended_size > frames.len() {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        frames.drain(..extended_size);
+    }
+    Ok(Some(Tag { version, revision, size, frames }))
+}
+
+struct Frame<'a> {
+    raw: &'a [u8],
+    id: &'a [u8],
+    flags: u8,
+    data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Reads the next frame from `frames`, or returns `None` if the padding was reached.
+    fn read(frames: &mut &'a [u8], version: u8) -> Result<Option<Self>, Error> {
+        if frames.len() < 10 || frames[0] == 0 {
+            return Ok(None);
+        }
+        let size = match version {
+            3 => u32::from_be_bytes(frames[4..8].try_into().unwrap()),
+            _ => syncsafe(&frames[4..8]),
+        } as usize;
+        if size > frames.len() - 10 {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        let (raw, rest) = frames.split_at(10 + size);
+        *frames = rest;
+        Ok(Some(Self { raw, id: &raw[0..4], flags: raw[9], data: &raw[10..] }))
+    }
+
+    /// Returns the tags stored in the frame, if it belongs to MemeDB.
+    fn tags(&self, version: u8) -> Option<Vec<u8>> {
+        if self.id != TAGS_FRAME {
+            return None;
+        }
+        // Compressed and encrypted frames are never written by MemeDB
+        let (skipped, unsynchronised) = match version {
+            3 if self.flags & 0xC0 != 0 => return None,
+            3 => (usize::from(self.flags & 0x20 != 0), false),
+            _ if self.flags & 0x0C != 0 => return None,
+            _ => {
+                let group = usize::from(self.flags & 0x40 != 0);
+                let length = 4 * usize::from(self.flags & 0x01 != 0);
+                (group + length, self.flags & 0x02 != 0)
+            }
+        };
+        let data = self.data.get(skipped..)?;
+        let data = if unsynchronised { resynchronise(data) } else { data.to_vec() };
+        data.strip_prefix(TAGS_OWNER).map(<[u8]>::to_vec)
+    }
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let tag = match read_tag(src) {
+        Ok(Some(tag)) => tag,
+        Ok(None) | Err(Error::Mp3UnsupportedVersion(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut frames = &tag.frames[..];
+    while let Some(frame) = Frame::read(&mut frames, tag.version)? {
+        if let Some(tags) = frame.tags(tag.version) {
+            return decode_tags(&mut &tags[..]);
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let tag = read_tag(src)?;
+    let (version, revision, size) = match &tag {
+        Some(tag) => (tag.version, tag.revision, tag.size),
+        None => (NEW_VERSION, 0, 0),
+    };
+    let mut frames = Vec::new();
+    if let Some(tag) = &tag {
+        let mut tag_frames = &tag.frames[..];
+        while let Some(frame) = Frame::read(&mut tag_frames, version)? {
+            if frame.tags(version).is_none() {
+                frames.extend_from_slice(frame.raw);
+            }
+        }
+    }
+
+    let mut tags_bytes = TAGS_OWNER.to_vec();
+    encode_tags(tags, &mut tags_bytes)?;
+    if tags_bytes.len() > MAX_SYNCSAFE {
+        return Err(Error::TagsTooLarge);
+    }
+    frames.extend_from_slice(TAGS_FRAME);
+    match version {
+        3 => frames.extend((tags_bytes.len() as u32).to_be_bytes()),
+        _ => frames.extend(to_syncsafe(tags_bytes.len() as u32)),
+    }
+    frames.extend([0, 0]);
+    frames.extend(tags_bytes);
+    // Keeping the same size lets the tag be rewritten in place
+    let padding = size.saturating_sub(frames.len());
+    let size = frames.len() + padding;
+    if size > MAX_SYNCSAFE {
+        return Err(Error::TagsTooLarge);
+    }
+
+    dest.write_all(MAGIC)?;
+    dest.write_all(&[version, revision, 0])?;
+    dest.write_all(&to_syncsafe(size as u32))?;
+    dest.write_all(&frames)?;
+    std::io::copy(&mut std::io::repeat(0).take(padding as u64), dest)?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MPEG_FRAME: &[&[u8]] = &[&[0xFF, 0xFB, 0x90, 0x00], &[0; 413]];
+    const COUNTER: &[&[u8]] = &[b"PCNT", &[0, 0, 0, 4], &[0, 0], &[0, 0, 0, 0xFF]];
+    const TAGS: &[&[u8]] =
+        &[b"PRIV", &[0, 0, 0, 12], &[0, 0], TAGS_OWNER, &[0x83, b'f', b'o', b'o', 0]];
+
+    #[test]
+    fn mpeg_detection() {
+        let is_mpeg = |src: &[u8]| is_mpeg(&src[..2], &mut &src[2..]).unwrap();
+        assert!(is_mpeg(&[MPEG_FRAME, MPEG_FRAME].concat().concat()));
+        assert!(!is_mpeg(&MPEG_FRAME.concat()));
+        assert!(!is_mpeg(&[&[0xFF, 0xFB, 0xF0, 0x00][..], &[0; 413]].concat()));
+    }
+
+    #[test]
+    fn bare_stream() {
+        let src = &MPEG_FRAME.concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let expected = &[MAGIC, &[3, 0, 0, 0, 0, 0, 22], &TAGS.concat(), src].concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn unsynchronised_extended_header() {
+        // Unsynchronisation inserts a zero byte after the `0xFF` byte of the counter
+        let extended: &[u8] = &[0, 0, 0, 6, 0, 0, 0, 0, 0, 0];
+        let header: &[u8] = &[3, 0, UNSYNCHRONISATION | EXTENDED_HEADER, 0, 0, 0, 50];
+        let tag: &[&[u8]] = &[MAGIC, header, extended, &COUNTER.concat(), &[0], &TAGS.concat()];
+        let src = &[&tag.concat(), &[0; 3][..], &MPEG_FRAME.concat()].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), ["foo"]);
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let tag: &[&[u8]] = &[MAGIC, &[3, 0, 0, 0, 0, 0, 50], &COUNTER.concat(), &TAGS.concat()];
+        let expected = &[&tag.concat(), &[0; 14][..], &MPEG_FRAME.concat()].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        // ID3v2.2 frames have 3 byte identifiers and 3 byte sizes
+        let tag: &[&[u8]] = &[MAGIC, &[2, 0, 0, 0, 0, 0, 10], b"TT2", &[0, 0, 4], b"\0foo"];
+        let src = &[tag, MPEG_FRAME].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let result = write_tags(&mut Cursor::new(src), &mut Vec::new(), ["foo"]);
+        assert!(matches!(result, Err(Error::Mp3UnsupportedVersion(2))));
+    }
+}
+
+crate::utils::standard_tests!("mp3");
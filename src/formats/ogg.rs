@@ -0,0 +1,444 @@
+//! # Ogg
+//!
+//! Ogg data is organized in pages. Each page is structured as follows:
+//!
+//! - 4 byte capture pattern, `OggS`.
+//! - 1 byte version, which is always 0.
+//! - 1 byte of flags, marking if the page continues a packet, or begins or ends a stream.
+//! - 8 byte little endian granule position, a codec-specific timestamp.
+//! - 4 byte little endian serial number identifying the logical stream of the page.
+//! - 4 byte little endian sequence number of the page within its logical stream.
+//! - 4 byte CRC-32 checksum of the whole page, calculated with the checksum field set to 0.
+//! - 1 byte number of segments in the page.
+//! - The lacing table, with 1 byte for the length of each segment.
+//! - The segments themselves.
+//!
+//! Packets are split across segments, and a packet ends with the first segment shorter than 255
+//! bytes. Packets can also continue across pages of the same logical stream.
+//!
+//! Vorbis, Opus and Theora streams all start with an identification packet alone on its own page,
+//! followed by a comment packet storing `NAME=value` text fields, and finish their header packets
+//! on a page boundary before any data packets begin.
+//!
+//! MemeDB stores its tags as `MEMEDB` fields in the comment packet of the first logical stream,
+//! with one field per tag. An empty set of tags is marked with an empty `MEMEDB_EMPTY` field
+//! instead. Since the comment packet may change size, its pages are rebuilt, and later pages of the
+//! same stream get their sequence numbers and checksums updated.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for Ogg](https://en.wikipedia.org/wiki/Ogg)
+//! - [RFC 3533, the Ogg specification](https://www.rfc-editor.org/rfc/rfc3533)
+//! - [Vorbis comment specification](https://xiph.org/vorbis/doc/v-comment.html)
+//! - [RFC 7845, Ogg encapsulation for Opus](https://www.rfc-editor.org/rfc/rfc7845)
+//! - [Theora specification](https://www.theora.org/doc/Theora.pdf)
+
+pub(crate) const MAGIC: &[u8] = b"OggS";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{or_eof, read_heap, read_stack},
+    Error,
+};
+use std::io::{Read, Seek, Write};
+
+const TAGS_FIELD: &[u8] = b"MEMEDB=";
+const EMPTY_FIELD: &[u8] = b"MEMEDB_EMPTY=";
+
+const CONTINUED: u8 = 0x01;
+const BEGIN_OF_STREAM: u8 = 0x02;
+const END_OF_STREAM: u8 = 0x04;
+
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::Algorithm {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0x00000000,
+    refin: false,
+    refout: false,
+    xorout: 0x00000000,
+    check: 0x89a1897f,
+    residue: 0x00000000,
+});
+
+#[derive(Copy, Clone)]
+enum Codec {
+    Vorbis,
+    Opus,
+    Theora,
+}
+
+impl Codec {
+    fn new(identification: &[u8]) -> Option<Self> {
+        match identification {
+            [b'\x01', b'v', b'o', b'r', b'b', b'i', b's', ..] => Some(Self::Vorbis),
+            [b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', ..] => Some(Self::Opus),
+            [b'\x80', b't', b'h', b'e', b'o', b'r', b'a', ..] => Some(Self::Theora),
+            _ => None,
+        }
+    }
+
+    fn comment_magic(self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+            Self::Theora => b"\x81theora",
+        }
+    }
+
+    fn header_packets(self) -> usize {
+        match self {
+            Self::Vorbis | Self::Theora => 3,
+            Self::Opus => 2,
+        }
+    }
+}
+
+struct Page {
+    header: [u8; 27],
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn new(flags: u8, granule: u64, serial: u32, sequence: u32) -> Self {
+        let mut header = [0; 27];
+        header[0..4].copy_from_slice(MAGIC);
+        header[5] = flags;
+        header[6..14].copy_from_slice(&granule.to_le_bytes());
+        header[14..18].copy_from_slice(&serial.to_le_bytes());
+        header[18..22].copy_from_slice(&sequence.to_le_bytes());
+        Self { header, lacing: Vec::new(), data: Vec::new() }
+    }
+
+    fn read(src: &mut impl Read) -> Result<Option<Self>, Error> {
+        let Some(header) = or_eof(read_stack::<27>(src))? else {
+            return Ok(None);
+        };
+        if &header[0..4] != MAGIC || header[4] != 0 {
+            return Err(Error::OggInvalidPage);
+        }
+        let lacing = read_heap(src, header[26] as usize)?;
+        let data = read_heap(src, lacing.iter().map(|&n| n as usize).sum())?;
+        Ok(Some(Self { header, lacing, data }))
+    }
+
+    fn write(&self, dest: &mut impl Write) -> Result<(), std::io::Error> {
+        dest.write_all(&self.header)?;
+        dest.write_all(&self.lacing)?;
+        dest.write_all(&self.data)
+    }
+
+    fn flags(&self) -> u8 {
+        self.header[5]
+    }
+
+    fn serial(&self) -> u32 {
+        u32::from_le_bytes(self.header[14..18].try_into().unwrap())
+    }
+
+    fn sequence(&self) -> u32 {
+        u32::from_le_bytes(self.header[18..22].try_into().unwrap())
+    }
+
+    fn set_sequence(&mut self, sequence: u32) {
+        self.header[18..22].copy_from_slice(&sequence.to_le_bytes());
+        self.update_checksum();
+    }
+
+    fn update_checksum(&mut self) {
+        self.header[22..26].fill(0);
+        self.header[26] = self.lacing.len() as u8;
+        let mut digest = CRC.digest();
+        digest.update(&self.header);
+        digest.update(&self.lacing);
+        digest.update(&self.data);
+        self.header[22..26].copy_from_slice(&digest.finalize().to_le_bytes());
+    }
+
+    /// Appends the packets ending in this page to `packets`, keeping unfinished ones in `current`.
+    fn packets(&self, packets: &mut Vec<Vec<u8>>, current: &mut Vec<u8>) {
+        let mut data = &self.data[..];
+        for &n in &self.lacing {
+            current.extend_from_slice(&data[..n as usize]);
+            data = &data[n as usize..];
+            if n < 255 {
+                packets.push(std::mem::take(current));
+            }
+        }
+    }
+}
+
+/// Splits `packets` into as many pages as needed, starting from the `sequence` page.
+fn paginate(packets: &[Vec<u8>], serial: u32, sequence: u32) -> Vec<Page> {
+    let mut pages = vec![Page::new(0, 0, serial, sequence)];
+    for packet in packets {
+        let mut segments: Vec<&[u8]> = packet.chunks(255).collect();
+        // Packets with a length multiple of 255 need an extra empty segment to mark their end
+        if packet.len() % 255 == 0 {
+            segments.push(&[]);
+        }
+        for (i, segment) in segments.iter().enumerate() {
+            if pages.last().unwrap().lacing.len() == 255 {
+                let flags = if i == 0 { 0 } else { CONTINUED };
+                pages.push(Page::new(flags, 0, serial, sequence.wrapping_add(pages.len() as u32)));
+            }
+            let page = pages.last_mut().unwrap();
+            page.lacing.push(segment.len() as u8);
+            page.data.extend_from_slice(segment);
+        }
+    }
+    for page in &mut pages {
+        // Pages where no packet ends have no granule position
+        if page.lacing.iter().all(|&n| n == 255) {
+            page.header[6..14].fill(0xFF);
+        }
+        page.update_checksum();
+    }
+    pages
+}
+
+/// Takes the next `n` bytes from `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if data.len() < n {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    let (taken, rest) = data.split_at(n);
+    *data = rest;
+    Ok(taken)
+}
+
+struct Comments<'a> {
+    vendor: &'a [u8],
+    fields: Vec<&'a [u8]>,
+    rest: &'a [u8],
+}
+
+impl<'a> Comments<'a> {
+    fn parse(mut packet: &'a [u8], codec: Codec) -> Result<Self, Error> {
+        if take(&mut packet, codec.comment_magic().len())? != codec.comment_magic() {
+            return Err(Error::OggUnsupportedStream);
+        }
+        let vendor_len = u32::from_le_bytes(take(&mut packet, 4)?.try_into().unwrap());
+        let vendor = take(&mut packet, vendor_len as usize)?;
+        let count = u32::from_le_bytes(take(&mut packet, 4)?.try_into().unwrap());
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            let len = u32::from_le_bytes(take(&mut packet, 4)?.try_into().unwrap());
+            fields.push(take(&mut packet, len as usize)?);
+        }
+        Ok(Self { vendor, fields, rest: packet })
+    }
+}
+
+/// Field names are case insensitive.
+fn has_name(field: &[u8], name: &[u8]) -> bool {
+    field.len() >= name.len() && field[..name.len()].eq_ignore_ascii_case(name)
+}
+
+/// Reads the first page of the file, which must have the identification packet of a supported
+/// codec alone.
+fn read_first_page(src: &mut impl Read) -> Result<(Page, Codec), Error> {
+    let Some(page) = Page::read(src)? else {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    };
+    let mut packets = Vec::new();
+    page.packets(&mut packets, &mut Vec::new());
+    match (page.flags() & BEGIN_OF_STREAM, &packets[..]) {
+        (BEGIN_OF_STREAM, [packet]) if page.lacing.last() != Some(&255) => {
+            let codec = Codec::new(packet).ok_or(Error::OggUnsupportedStream)?;
+            Ok((page, codec))
+        }
+        _ => Err(Error::OggUnsupportedStream),
+    }
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let (first_page, codec) = read_first_page(src)?;
+    let mut packets = Vec::new();
+    let mut current = Vec::new();
+    while packets.is_empty() {
+        let Some(page) = Page::read(src)? else {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        };
+        if page.serial() == first_page.serial() {
+            page.packets(&mut packets, &mut current);
+        }
+    }
+    let comments = Comments::parse(&packets[0], codec)?;
+    let mut tags = Vec::new();
+    for field in comments.fields.into_iter().filter(|f| has_name(f, TAGS_FIELD)) {
+        tags.push(String::from_utf8(field[TAGS_FIELD.len()..].to_vec())?);
+    }
+    Ok(tags)
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let (first_page, codec) = read_first_page(src)?;
+    first_page.write(dest)?;
+    let serial = first_page.serial();
+
+    // Pages from other streams can be passed through, since they don't depend on ours
+    let mut packets = Vec::new();
+    let mut current = Vec::new();
+    let mut old_pages = 0;
+    let mut flags = 0;
+    while packets.len() < codec.header_packets() - 1 {
+        let Some(page) = Page::read(src)? else {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        };
+        if page.serial() == serial {
+            page.packets(&mut packets, &mut current);
+            old_pages += 1;
+            flags = page.flags();
+        } else {
+            page.write(dest)?;
+        }
+    }
+    if packets.len() != codec.header_packets() - 1 || !current.is_empty() {
+        return Err(Error::OggUnsupportedStream);
+    }
+
+    let comments = Comments::parse(&packets[0], codec)?;
+    let fields: Vec<&[u8]> = comments
+        .fields
+        .into_iter()
+        .filter(|f| !has_name(f, TAGS_FIELD) && !has_name(f, EMPTY_FIELD))
+        .collect();
+    let mut tags: Vec<Vec<u8>> =
+        tags.into_iter().map(|t| [TAGS_FIELD, t.as_ref().as_bytes()].concat()).collect();
+    if tags.is_empty() {
+        tags.push(EMPTY_FIELD.to_vec());
+    }
+    let count = u32::try_from(fields.len() + tags.len()).map_err(|_| Error::TagsTooLarge)?;
+    let mut packet = codec.comment_magic().to_vec();
+    packet.extend((comments.vendor.len() as u32).to_le_bytes());
+    packet.extend(comments.vendor);
+    packet.extend(count.to_le_bytes());
+    for field in fields.into_iter().chain(tags.iter().map(Vec::as_slice)) {
+        let len = u32::try_from(field.len()).map_err(|_| Error::TagsTooLarge)?;
+        packet.extend(len.to_le_bytes());
+        packet.extend(field);
+    }
+    packet.extend(comments.rest);
+    packets[0] = packet;
+
+    let mut pages = paginate(&packets, serial, first_page.sequence().wrapping_add(1));
+    let new_pages = pages.len();
+    if flags & END_OF_STREAM != 0 {
+        let page = pages.last_mut().unwrap();
+        page.header[5] |= END_OF_STREAM;
+        page.update_checksum();
+    }
+    for page in pages {
+        page.write(dest)?;
+    }
+
+    if new_pages == old_pages {
+        std::io::copy(src, dest)?;
+        return Ok(());
+    }
+    while let Some(mut page) = Page::read(src)? {
+        if page.serial() == serial {
+            let sequence = page.sequence().wrapping_add(new_pages as u32);
+            page.set_sequence(sequence.wrapping_sub(old_pages as u32));
+        }
+        page.write(dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const IDENTIFICATION: &[u8] = b"\x01vorbis";
+    const COMMENT: &[&[u8]] = &[b"\x03vorbis", &[1, 0, 0, 0], b"v"];
+    const FIELD: &[&[u8]] = &[&[10, 0, 0, 0], b"MEMEDB=foo"];
+    const SETUP: &[u8] = b"\x05vorbis";
+    const DATA: &[u8] = &[0x00, 0x01];
+
+    fn stream(comment: &[u8]) -> Vec<u8> {
+        let mut pages = paginate(&[IDENTIFICATION.to_vec()], 1, 0);
+        pages[0].header[5] = BEGIN_OF_STREAM;
+        pages[0].update_checksum();
+        pages.extend(paginate(&[comment.to_vec(), SETUP.to_vec()], 1, 1));
+        pages.extend(paginate(&[DATA.to_vec()], 1, 2));
+        let mut bytes = Vec::new();
+        for page in pages {
+            page.write(&mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    fn pages(mut src: &[u8]) -> Vec<Page> {
+        std::iter::from_fn(|| Page::read(&mut src).unwrap()).collect()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(CRC.checksum(b"123456789"), 0x89a1897f);
+    }
+
+    #[test]
+    fn vorbis_framing_bit() {
+        let src = stream(&[&COMMENT.concat(), &[0, 0, 0, 0][..], &[1]].concat());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, ["foo"]).unwrap();
+        let expected =
+            stream(&[&COMMENT.concat(), &[1, 0, 0, 0][..], &FIELD.concat(), &[1]].concat());
+        assert_eq!(dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn empty_tags() {
+        let src = stream(&[&COMMENT.concat(), &[1, 0, 0, 0][..], &FIELD.concat(), &[1]].concat());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, [] as [&str; 0]).unwrap();
+        let field: &[&[u8]] = &[&[13, 0, 0, 0], EMPTY_FIELD];
+        let expected =
+            stream(&[&COMMENT.concat(), &[1, 0, 0, 0][..], &field.concat(), &[1]].concat());
+        assert_eq!(dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn empty_string_tags() {
+        let src = stream(&[&COMMENT.concat(), &[0, 0, 0, 0][..], &[1]].concat());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, ["", "foo"]).unwrap();
+        let field: &[&[u8]] = &[&[7, 0, 0, 0], TAGS_FIELD];
+        let fields = [field, FIELD].concat().concat();
+        let expected = stream(&[&COMMENT.concat(), &[2, 0, 0, 0][..], &fields, &[1]].concat());
+        assert_eq!(dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["", "foo"]);
+    }
+
+    #[test]
+    fn renumber_pages() {
+        let tag = "a".repeat(255 * 255);
+        let src = stream(&[&COMMENT.concat(), &[0, 0, 0, 0][..], &[1]].concat());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, [&tag]).unwrap();
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), [tag]);
+        let mut sequences = Vec::new();
+        for mut page in pages(&dest) {
+            let checksum = page.header;
+            page.update_checksum();
+            assert_eq!(page.header, checksum);
+            sequences.push(page.sequence());
+        }
+        assert_eq!(sequences, [0, 1, 2, 3]);
+    }
+}
+
+crate::utils::standard_tests!("opus");
@@ -0,0 +1,862 @@
+//! # Portable Document Format
+//!
+//! PDF files start with a `%PDF-` header, followed by a body of numbered objects. Objects are found
+//! through cross-reference sections, which map object numbers to byte offsets, and each section is
+//! followed by a trailer dictionary. The file ends with a `startxref` keyword, which points to the
+//! last cross-reference section.
+//!
+//! PDF files can be modified through incremental updates, which append new versions of objects
+//! along with a new cross-reference section, whose trailer points back to the previous section
+//! through its `Prev` entry. Readers follow this chain starting from the last trailer, so the most
+//! recent version of each object wins. Since PDF 1.5, cross-reference sections may also be stored
+//! as compressed streams, and objects may be stored inside compressed object streams.
+//!
+//! MemeDB stores its tags in a `MemeDB` entry of the document information dictionary, as an array
+//! of UTF-8 text strings. The dictionary is written in an incremental update, which carries over
+//! every other entry of the previous dictionary. This way the original bytes of the file are never
+//! modified, and existing digital signatures remain valid. If the last incremental update of the
+//! file was written by MemeDB, it gets replaced instead of piling up a new one.
+//!
+//! Encrypted files are not supported, since the tags would have to be encrypted as well.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for PDF](https://en.wikipedia.org/wiki/PDF)
+//! - [PDF 1.7 specification](https://opensource.adobe.com/dc-acrobat-sdk-docs/pdfstandards/PDF32000_2008.pdf)
+
+pub(crate) const MAGIC: &[u8] = b"%PDF-";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{passthrough, read_byte, read_exact_heap},
+    Error,
+};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+};
+
+const TAGS_KEY: &[u8] = b"MemeDB";
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// How far from the end of the file to look for the `startxref` keyword.
+const STARTXREF_SEARCH: u64 = 1024;
+const BUFFER_SIZE: u64 = 0x1000;
+const MAX_DEPTH: usize = 32;
+const MAX_STREAM_SIZE: usize = 0x4000000;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Object {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    /// Real numbers are kept as they were written, since they are never used for anything.
+    Real(Vec<u8>),
+    String(Vec<u8>),
+    Name(Vec<u8>),
+    Array(Vec<Object>),
+    Dictionary(Dictionary),
+    Reference(u64, u64),
+}
+
+impl Object {
+    fn write(&self, dest: &mut Vec<u8>) {
+        match self {
+            Object::Null => dest.extend_from_slice(b"null"),
+            Object::Bool(b) => dest.extend_from_slice(if *b { b"true" } else { b"false" }),
+            Object::Integer(n) => dest.extend_from_slice(n.to_string().as_bytes()),
+            Object::Real(r) => dest.extend_from_slice(r),
+            Object::String(s) => {
+                dest.push(b'<');
+                for b in s {
+                    dest.extend_from_slice(format!("{b:02X}").as_bytes());
+                }
+                dest.push(b'>');
+            }
+            Object::Name(n) => {
+                dest.push(b'/');
+                for b in n {
+                    if is_regular(*b) && *b != b'#' && (b'!'..=b'~').contains(b) {
+                        dest.push(*b);
+                    } else {
+                        dest.extend_from_slice(format!("#{b:02X}").as_bytes());
+                    }
+                }
+            }
+            Object::Array(a) => {
+                dest.push(b'[');
+                for (i, o) in a.iter().enumerate() {
+                    if i > 0 {
+                        dest.push(b' ');
+                    }
+                    o.write(dest);
+                }
+                dest.push(b']');
+            }
+            Object::Dictionary(d) => {
+                dest.extend_from_slice(b"<<");
+                for (k, v) in &d.0 {
+                    dest.push(b' ');
+                    Object::Name(k.clone()).write(dest);
+                    dest.push(b' ');
+                    v.write(dest);
+                }
+                dest.extend_from_slice(b" >>");
+            }
+            Object::Reference(n, g) => dest.extend_from_slice(format!("{n} {g} R").as_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Dictionary(Vec<(Vec<u8>, Object)>);
+
+impl Dictionary {
+    fn get(&self, key: &[u8]) -> Option<&Object> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn uint(&self, key: &[u8]) -> Option<u64> {
+        match self.get(key) {
+            Some(Object::Integer(n)) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte)
+        && !matches!(byte, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Tokenizer for PDF objects, which reads from `src` as needed.
+struct Lexer<'a, R> {
+    src: &'a mut R,
+    offset: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read + Seek> Lexer<'a, R> {
+    fn new(src: &'a mut R, offset: u64) -> Result<Self, std::io::Error> {
+        src.seek(SeekFrom::Start(offset))?;
+        Ok(Self { src, offset, buf: Vec::new(), pos: 0 })
+    }
+
+    fn position(&self) -> u64 {
+        self.offset + self.pos as u64
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, std::io::Error> {
+        if self.pos == self.buf.len() {
+            self.src.by_ref().take(BUFFER_SIZE).read_to_end(&mut self.buf)?;
+        }
+        Ok(self.buf.get(self.pos).copied())
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        let byte = self.peek()?.ok_or(Error::PdfInvalidSyntax)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), std::io::Error> {
+        while let Some(byte) = self.peek()? {
+            if byte == b'%' {
+                while self.peek()?.is_some_and(|b| b != b'\r' && b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if is_whitespace(byte) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn token(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        self.skip_whitespace()?;
+        let start = self.pos;
+        while self.peek()?.is_some_and(is_regular) {
+            self.pos += 1;
+        }
+        Ok(self.buf[start..self.pos].to_vec())
+    }
+
+    fn keyword(&mut self, keyword: &[u8]) -> Result<(), Error> {
+        if self.token()? == keyword {
+            Ok(())
+        } else {
+            Err(Error::PdfInvalidSyntax)
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, Error> {
+        let token = self.token()?;
+        std::str::from_utf8(&token).ok().and_then(|t| t.parse().ok()).ok_or(Error::PdfInvalidSyntax)
+    }
+
+    /// Skips the `number generation obj` header of an indirect object.
+    fn object_header(&mut self) -> Result<(), Error> {
+        self.uint()?;
+        self.uint()?;
+        self.keyword(b"obj")
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Object, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::PdfInvalidSyntax);
+        }
+        self.skip_whitespace()?;
+        match self.peek()?.ok_or(Error::PdfInvalidSyntax)? {
+            b'/' => {
+                self.pos += 1;
+                Ok(Object::Name(self.name()?))
+            }
+            b'(' => {
+                self.pos += 1;
+                Ok(Object::String(self.literal_string()?))
+            }
+            b'<' => {
+                self.pos += 1;
+                if self.peek()? == Some(b'<') {
+                    self.pos += 1;
+                    Ok(Object::Dictionary(self.dictionary_entries(depth)?))
+                } else {
+                    Ok(Object::String(self.hex_string()?))
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut array = Vec::new();
+                loop {
+                    self.skip_whitespace()?;
+                    if self.peek()? == Some(b']') {
+                        self.pos += 1;
+                        return Ok(Object::Array(array));
+                    }
+                    array.push(self.object(depth + 1)?);
+                }
+            }
+            _ => {
+                let token = self.token()?;
+                match &token[..] {
+                    b"null" => Ok(Object::Null),
+                    b"true" => Ok(Object::Bool(true)),
+                    b"false" => Ok(Object::Bool(false)),
+                    _ => self.number(token),
+                }
+            }
+        }
+    }
+
+    /// Parses a number, or a reference if it's followed by a generation number and `R`.
+    fn number(&mut self, token: Vec<u8>) -> Result<Object, Error> {
+        let Some(n) = std::str::from_utf8(&token).ok().and_then(|t| t.parse().ok()) else {
+            let is_real = !token.is_empty() && token.iter().all(|b| b"+-.0123456789".contains(b));
+            return if is_real { Ok(Object::Real(token)) } else { Err(Error::PdfInvalidSyntax) };
+        };
+        let pos = self.pos;
+        if let (Ok(number), Ok(generation)) = (u64::try_from(n), self.uint()) {
+            if self.token()? == b"R" {
+                return Ok(Object::Reference(number, generation));
+            }
+        }
+        self.pos = pos;
+        Ok(Object::Integer(n))
+    }
+
+    fn name(&mut self) -> Result<Vec<u8>, Error> {
+        let mut name = Vec::new();
+        while let Some(byte) = self.peek()?.filter(|b| is_regular(*b)) {
+            self.pos += 1;
+            if byte == b'#' {
+                let high = hex_digit(self.next()?).ok_or(Error::PdfInvalidSyntax)?;
+                let low = hex_digit(self.next()?).ok_or(Error::PdfInvalidSyntax)?;
+                name.push(high << 4 | low);
+            } else {
+                name.push(byte);
+            }
+        }
+        Ok(name)
+    }
+
+    fn literal_string(&mut self) -> Result<Vec<u8>, Error> {
+        let mut string = Vec::new();
+        let mut depth = 0;
+        loop {
+            match self.next()? {
+                b'(' => {
+                    depth += 1;
+                    string.push(b'(');
+                }
+                b')' if depth == 0 => return Ok(string),
+                b')' => {
+                    depth -= 1;
+                    string.push(b')');
+                }
+                b'\\' => match self.next()? {
+                    b'n' => string.push(b'\n'),
+                    b'r' => string.push(b'\r'),
+                    b't' => string.push(b'\t'),
+                    b'b' => string.push(b'\x08'),
+                    b'f' => string.push(b'\x0C'),
+                    b'\r' => {
+                        if self.peek()? == Some(b'\n') {
+                            self.pos += 1;
+                        }
+                    }
+                    b'\n' => (),
+                    d @ b'0'..=b'7' => {
+                        let mut code = d - b'0';
+                        for _ in 0..2 {
+                            match self.peek()? {
+                                Some(d @ b'0'..=b'7') => {
+                                    self.pos += 1;
+                                    code = code.wrapping_mul(8).wrapping_add(d - b'0');
+                                }
+                                _ => break,
+                            }
+                        }
+                        string.push(code);
+                    }
+                    b => string.push(b),
+                },
+                // Unescaped line endings are always read as a line feed
+                b'\r' => {
+                    if self.peek()? == Some(b'\n') {
+                        self.pos += 1;
+                    }
+                    string.push(b'\n');
+                }
+                b => string.push(b),
+            }
+        }
+    }
+
+    fn hex_string(&mut self) -> Result<Vec<u8>, Error> {
+        let mut digits = Vec::new();
+        loop {
+            match self.next()? {
+                b'>' => break,
+                b if is_whitespace(b) => (),
+                b => digits.push(hex_digit(b).ok_or(Error::PdfInvalidSyntax)?),
+            }
+        }
+        // A missing final digit is assumed to be zero
+        Ok(digits.chunks(2).map(|d| d[0] << 4 | d.get(1).unwrap_or(&0)).collect())
+    }
+
+    fn dictionary_entries(&mut self, depth: usize) -> Result<Dictionary, Error> {
+        let mut dictionary = Dictionary::default();
+        loop {
+            self.skip_whitespace()?;
+            match self.next()? {
+                b'>' if self.next()? == b'>' => return Ok(dictionary),
+                b'/' => {
+                    let key = self.name()?;
+                    let value = self.object(depth + 1)?;
+                    dictionary.0.push((key, value));
+                }
+                _ => return Err(Error::PdfInvalidSyntax),
+            }
+        }
+    }
+
+    fn dictionary(&mut self) -> Result<Dictionary, Error> {
+        match self.object(0)? {
+            Object::Dictionary(dictionary) => Ok(dictionary),
+            _ => Err(Error::PdfInvalidSyntax),
+        }
+    }
+
+    /// Skips the `stream` keyword and its line ending, returning the position of the stream data.
+    fn stream_start(&mut self) -> Result<u64, Error> {
+        self.keyword(b"stream")?;
+        if self.peek()? == Some(b'\r') {
+            self.pos += 1;
+        }
+        if self.peek()? == Some(b'\n') {
+            self.pos += 1;
+        }
+        Ok(self.position())
+    }
+}
+
+/// Reads a stream object at `offset`, returning its dictionary and its decoded data. Indirect
+/// lengths are resolved through `document`, if there's one.
+fn read_stream(
+    src: &mut (impl Read + Seek),
+    offset: u64,
+    document: Option<&Document>,
+) -> Result<(Dictionary, Vec<u8>), Error> {
+    let mut lexer = Lexer::new(src, offset)?;
+    lexer.object_header()?;
+    let dictionary = lexer.dictionary()?;
+    let start = lexer.stream_start()?;
+    let length = match (dictionary.get(b"Length"), document) {
+        (Some(Object::Integer(n)), _) => u64::try_from(*n).ok(),
+        (Some(Object::Reference(n, _)), Some(document)) => match document.direct_object(src, *n)? {
+            Some(Object::Integer(n)) => u64::try_from(n).ok(),
+            _ => None,
+        },
+        _ => None,
+    };
+    src.seek(SeekFrom::Start(start))?;
+    let data = read_exact_heap(src, length.ok_or(Error::PdfInvalidSyntax)?)?;
+    let data = decode_stream(&dictionary, data)?;
+    Ok((dictionary, data))
+}
+
+/// Applies the filters of a stream. Only Flate compression is supported, since it's what PDF
+/// writers use for cross-reference and object streams.
+fn decode_stream(dictionary: &Dictionary, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let single = |object: Option<&Object>| match object {
+        Some(Object::Array(a)) if a.len() <= 1 => Ok(a.first().cloned()),
+        Some(Object::Array(_)) => Err(Error::PdfUnsupportedStream),
+        o => Ok(o.cloned()),
+    };
+    let data = match single(dictionary.get(b"Filter"))? {
+        None => data,
+        Some(Object::Name(n)) if n == b"FlateDecode" => {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data, MAX_STREAM_SIZE)
+                .map_err(|_| Error::PdfInvalidSyntax)?
+        }
+        Some(_) => return Err(Error::PdfUnsupportedStream),
+    };
+    let Some(Object::Dictionary(parameters)) = single(dictionary.get(b"DecodeParms"))? else {
+        return Ok(data);
+    };
+    let is_default = |key: &[u8], value| parameters.get(key).is_none_or(|v| v == &value);
+    match parameters.uint(b"Predictor").unwrap_or(1) {
+        1 => Ok(data),
+        10..=15
+            if is_default(b"Colors", Object::Integer(1))
+                && is_default(b"BitsPerComponent", Object::Integer(8)) =>
+        {
+            let columns = parameters.uint(b"Columns").unwrap_or(1);
+            let columns = usize::try_from(columns).map_err(|_| Error::PdfInvalidSyntax)?;
+            unpredict(&data, columns.min(data.len()))
+        }
+        _ => Err(Error::PdfUnsupportedStream),
+    }
+}
+
+/// Reverses the PNG predictors, for rows of `columns` single byte samples.
+fn unpredict(data: &[u8], columns: usize) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; columns];
+    for row in data.chunks(columns + 1) {
+        let (filter, row) = row.split_first().ok_or(Error::PdfInvalidSyntax)?;
+        let start = decoded.len();
+        for (i, (byte, up)) in row.iter().zip(&previous).enumerate() {
+            let left = if i > 0 { decoded[start + i - 1] } else { 0 };
+            let up_left = if i > 0 { previous[i - 1] } else { 0 };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => *up,
+                3 => left.midpoint(*up),
+                4 => {
+                    let p = left as i16 + *up as i16 - up_left as i16;
+                    let (pa, pb, pc) = (
+                        (p - left as i16).abs(),
+                        (p - *up as i16).abs(),
+                        (p - up_left as i16).abs(),
+                    );
+                    if pa <= pb && pa <= pc {
+                        left
+                    } else if pb <= pc {
+                        *up
+                    } else {
+                        up_left
+                    }
+                }
+                _ => return Err(Error::PdfInvalidSyntax),
+            };
+            decoded.push(byte.wrapping_add(prediction));
+        }
+        previous[..row.len()].copy_from_slice(&decoded[start..]);
+    }
+    Ok(decoded)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Entry {
+    Free,
+    Offset(u64),
+    /// Objects stored inside an object stream, with the number of the stream.
+    Compressed(u64),
+}
+
+/// A cross-reference section along with its trailer.
+struct Section {
+    entries: Vec<(u64, Entry)>,
+    trailer: Dictionary,
+    is_stream: bool,
+}
+
+impl Section {
+    fn read(src: &mut (impl Read + Seek), offset: u64) -> Result<Self, Error> {
+        let mut lexer = Lexer::new(src, offset)?;
+        lexer.skip_whitespace()?;
+        if lexer.peek()? != Some(b'x') {
+            return Self::read_stream(src, offset);
+        }
+        lexer.keyword(b"xref")?;
+        let mut entries = Vec::new();
+        loop {
+            let token = lexer.token()?;
+            if token == b"trailer" {
+                break;
+            }
+            let first = std::str::from_utf8(&token).ok().and_then(|t| t.parse::<u64>().ok());
+            let first = first.ok_or(Error::PdfInvalidSyntax)?;
+            for i in 0..lexer.uint()? {
+                let offset = lexer.uint()?;
+                lexer.uint()?;
+                let entry = match &lexer.token()?[..] {
+                    b"n" => Entry::Offset(offset),
+                    b"f" => Entry::Free,
+                    _ => return Err(Error::PdfInvalidSyntax),
+                };
+                entries.push((first.checked_add(i).ok_or(Error::PdfInvalidSyntax)?, entry));
+            }
+        }
+        let trailer = lexer.dictionary()?;
+        // Hybrid files hide objects from older readers in a cross-reference stream
+        if let Some(offset) = trailer.uint(b"XRefStm") {
+            let hidden = Self::read_stream(src, offset)?.entries;
+            let mut hidden: Vec<_> =
+                hidden.into_iter().filter(|(_, e)| e != &Entry::Free).collect();
+            hidden.append(&mut entries);
+            entries = hidden;
+        }
+        Ok(Self { entries, trailer, is_stream: false })
+    }
+
+    fn read_stream(src: &mut (impl Read + Seek), offset: u64) -> Result<Self, Error> {
+        let (trailer, data) = read_stream(src, offset, None)?;
+        let widths = match trailer.get(b"W") {
+            Some(Object::Array(a)) if a.len() == 3 => a
+                .iter()
+                .map(|w| match w {
+                    Object::Integer(w @ 0..=8) => {
+                        usize::try_from(*w).map_err(|_| Error::PdfInvalidSyntax)
+                    }
+                    _ => Err(Error::PdfInvalidSyntax),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(Error::PdfInvalidSyntax),
+        };
+        let index = match trailer.get(b"Index") {
+            Some(Object::Array(a)) => a
+                .iter()
+                .map(|i| match i {
+                    Object::Integer(i) => u64::try_from(*i).map_err(|_| Error::PdfInvalidSyntax),
+                    _ => Err(Error::PdfInvalidSyntax),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![0, trailer.uint(b"Size").ok_or(Error::PdfInvalidSyntax)?],
+        };
+        let row_size = widths.iter().sum::<usize>().max(1);
+        let mut rows = data.chunks_exact(row_size);
+        let mut entries = Vec::new();
+        for subsection in index.chunks_exact(2) {
+            for (i, row) in (0..subsection[1]).zip(&mut rows) {
+                let mut fields = [0; 3];
+                let mut row = row;
+                for (field, width) in fields.iter_mut().zip(&widths) {
+                    let (bytes, rest) = row.split_at(*width);
+                    *field = bytes.iter().fold(0, |n, b| n << 8 | *b as u64);
+                    row = rest;
+                }
+                // The type defaults to 1 when its field is missing, and unknown types are ignored
+                let entry = match (widths[0], fields[0]) {
+                    (0, _) | (_, 1) => Entry::Offset(fields[1]),
+                    (_, 2) => Entry::Compressed(fields[1]),
+                    _ => Entry::Free,
+                };
+                let number = subsection[0].checked_add(i).ok_or(Error::PdfInvalidSyntax)?;
+                entries.push((number, entry));
+            }
+        }
+        Ok(Self { entries, trailer, is_stream: true })
+    }
+}
+
+/// All cross-reference sections of a file, starting from the last one.
+struct Document {
+    startxref: u64,
+    sections: Vec<Section>,
+    entries: HashMap<u64, Entry>,
+}
+
+impl Document {
+    fn read(src: &mut (impl Read + Seek)) -> Result<Self, Error> {
+        let len = src.seek(SeekFrom::End(0))?;
+        let tail_start = len.saturating_sub(STARTXREF_SEARCH);
+        src.seek(SeekFrom::Start(tail_start))?;
+        let mut tail = Vec::new();
+        src.read_to_end(&mut tail)?;
+        let i = tail.windows(9).rposition(|w| w == b"startxref").ok_or(Error::PdfInvalidSyntax)?;
+        let startxref = Lexer::new(src, tail_start + i as u64 + 9)?.uint()?;
+
+        let mut sections: Vec<Section> = Vec::new();
+        let mut offsets = vec![startxref];
+        while let Some(offset) = offsets.last() {
+            let section = Section::read(src, *offset)?;
+            match section.trailer.uint(b"Prev") {
+                Some(prev) if !offsets.contains(&prev) => offsets.push(prev),
+                Some(_) => return Err(Error::PdfInvalidSyntax),
+                None => offsets.clear(),
+            }
+            sections.push(section);
+        }
+
+        let mut entries = HashMap::new();
+        for section in &sections {
+            for (number, entry) in &section.entries {
+                entries.entry(*number).or_insert(*entry);
+            }
+        }
+        Ok(Self { startxref, sections, entries })
+    }
+
+    /// Returns the most recent value for a trailer entry.
+    fn trailer(&self, key: &[u8]) -> Option<&Object> {
+        self.sections.iter().find_map(|s| s.trailer.get(key))
+    }
+
+    /// Reads an object that isn't stored inside an object stream.
+    fn direct_object(
+        &self,
+        src: &mut (impl Read + Seek),
+        number: u64,
+    ) -> Result<Option<Object>, Error> {
+        match self.entries.get(&number) {
+            Some(Entry::Offset(offset)) => {
+                let mut lexer = Lexer::new(src, *offset)?;
+                lexer.object_header()?;
+                Ok(Some(lexer.object(0)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn object(&self, src: &mut (impl Read + Seek), number: u64) -> Result<Option<Object>, Error> {
+        let Some(Entry::Compressed(stream)) = self.entries.get(&number) else {
+            return self.direct_object(src, number);
+        };
+        let Some(Entry::Offset(offset)) = self.entries.get(stream) else {
+            return Ok(None);
+        };
+        let (dictionary, data) = read_stream(src, *offset, Some(self))?;
+        let count = dictionary.uint(b"N").ok_or(Error::PdfInvalidSyntax)?;
+        let first = dictionary.uint(b"First").ok_or(Error::PdfInvalidSyntax)?;
+        let mut data = Cursor::new(data);
+        let mut lexer = Lexer::new(&mut data, 0)?;
+        let mut object_offset = None;
+        for _ in 0..count {
+            let (n, offset) = (lexer.uint()?, lexer.uint()?);
+            if n == number {
+                object_offset = Some(first.checked_add(offset).ok_or(Error::PdfInvalidSyntax)?);
+                break;
+            }
+        }
+        match object_offset {
+            Some(offset) => Ok(Some(Lexer::new(&mut data, offset)?.object(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the document information dictionary, along with its reference if it has one.
+    fn info(
+        &self,
+        src: &mut (impl Read + Seek),
+    ) -> Result<(Option<(u64, u64)>, Dictionary), Error> {
+        let (reference, info) = match self.trailer(b"Info") {
+            Some(Object::Reference(n, g)) => (Some((*n, *g)), self.object(src, *n)?),
+            Some(o) => (None, Some(o.clone())),
+            None => (None, None),
+        };
+        match info {
+            Some(Object::Dictionary(info)) => Ok((reference, info)),
+            _ => Ok((reference, Dictionary::default())),
+        }
+    }
+
+    /// Returns the offset where the last incremental update starts, if it was written by MemeDB.
+    fn own_update(&self, info: Option<(u64, u64)>, info_dictionary: &Dictionary) -> Option<u64> {
+        let last = &self.sections[0];
+        match (&last.entries[..], info) {
+            ([(n, Entry::Offset(offset))], Some((info, _)))
+                if !last.is_stream
+                    && *n == info
+                    && last.trailer.get(b"XRefStm").is_none()
+                    && info_dictionary.get(TAGS_KEY).is_some()
+                    && *offset < self.startxref =>
+            {
+                Some(*offset)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let document = Document::read(src)?;
+    let (_, info) = document.info(src)?;
+    match info.get(TAGS_KEY) {
+        Some(Object::Array(tags)) => tags
+            .iter()
+            .map(|tag| match tag {
+                Object::String(s) => {
+                    Ok(String::from_utf8(s.strip_prefix(UTF8_BOM).unwrap_or(s).to_vec())?)
+                }
+                _ => Err(Error::PdfInvalidSyntax),
+            })
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let len = src.seek(SeekFrom::End(0))?;
+    let document = Document::read(src)?;
+    if document.trailer(b"Encrypt").is_some() {
+        return Err(Error::PdfEncrypted);
+    }
+    let (reference, mut info) = document.info(src)?;
+    let size = document.sections[0].trailer.uint(b"Size").ok_or(Error::PdfInvalidSyntax)?;
+
+    // A previous update written by MemeDB is dropped, since it's redundant
+    let (end, prev) = match document.own_update(reference, &info) {
+        Some(offset) => {
+            let prev = document.sections[0].trailer.uint(b"Prev");
+            (offset, prev.ok_or(Error::PdfInvalidSyntax)?)
+        }
+        None => (len, document.startxref),
+    };
+    if end == 0 || end > len {
+        return Err(Error::PdfInvalidSyntax);
+    }
+
+    // The information dictionary keeps its number if it has one
+    let (number, generation) = reference.unwrap_or((size, 0));
+    let size = size.max(number.checked_add(1).ok_or(Error::PdfInvalidSyntax)?);
+    info.0.retain(|(k, _)| k != TAGS_KEY);
+    let tags = tags.into_iter().map(|t| Object::String([UTF8_BOM, t.as_ref().as_bytes()].concat()));
+    info.0.push((TAGS_KEY.to_vec(), Object::Array(tags.collect())));
+
+    let mut trailer = Dictionary::default();
+    trailer.0.push((b"Size".to_vec(), Object::Integer(size as i64)));
+    for key in [&b"Root"[..], b"ID"] {
+        if let Some(value) = document.trailer(key) {
+            trailer.0.push((key.to_vec(), value.clone()));
+        }
+    }
+    trailer.0.push((b"Info".to_vec(), Object::Reference(number, generation)));
+    trailer.0.push((b"Prev".to_vec(), Object::Integer(prev as i64)));
+
+    src.seek(SeekFrom::Start(end - 1))?;
+    let mut update = Vec::new();
+    if !matches!(read_byte(src)?, b'\r' | b'\n') {
+        update.push(b'\n');
+    }
+    let object_offset = end + update.len() as u64;
+    writeln!(update, "{number} {generation} obj")?;
+    Object::Dictionary(info).write(&mut update);
+    update.extend_from_slice(b"\nendobj\n");
+    let xref_offset = end + update.len() as u64;
+    write!(update, "xref\n{number} 1\n{object_offset:010} {generation:05} n\r\ntrailer\n")?;
+    Object::Dictionary(trailer).write(&mut update);
+    writeln!(update, "\nstartxref\n{xref_offset}\n%%EOF")?;
+
+    src.seek(SeekFrom::Start(0))?;
+    passthrough(src, dest, end)?;
+    dest.write_all(&update)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"%PDF-1.4\n1 0 obj\n<< /Type /Catalog >>\nendobj\n";
+    const XREF: &[u8] = b"xref\n0 2\n0000000000 65535 f\r\n0000000009 00000 n\r\n";
+    const TRAILER: &[u8] = b"trailer\n<< /Size 2 /Root 1 0 R >>\nstartxref\n45\n%%EOF";
+
+    fn write(src: &[u8], tags: &[&str]) -> Vec<u8> {
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, tags).unwrap();
+        dest
+    }
+
+    #[test]
+    fn incremental_update() {
+        let src = &[BODY, XREF, TRAILER].concat();
+        let tagged = write(src, &["foo"]);
+        assert!(tagged.starts_with(src));
+        assert_eq!(read_tags(&mut Cursor::new(&tagged)).unwrap(), ["foo"]);
+        // An update written by MemeDB is replaced
+        let retagged = write(&tagged, &["bar"]);
+        assert_eq!(retagged, write(src, &["bar"]));
+        // But updates written by anyone else are kept
+        let prev = Document::read(&mut Cursor::new(&tagged)).unwrap().startxref;
+        let (object, xref) = (tagged.len(), tagged.len() + 20);
+        let update = format!(
+            "3 0 obj\nnull\nendobj\nxref\n3 1\n{object:010} 00000 n\r\ntrailer\n\
+            << /Size 4 /Root 1 0 R /Info 2 0 R /Prev {prev} >>\nstartxref\n{xref}\n%%EOF\n"
+        );
+        let updated = &[&tagged, update.as_bytes()].concat();
+        assert_eq!(read_tags(&mut Cursor::new(updated)).unwrap(), ["foo"]);
+        let retagged = write(updated, &["bar"]);
+        assert!(retagged.starts_with(updated));
+        assert_eq!(read_tags(&mut Cursor::new(&retagged)).unwrap(), ["bar"]);
+    }
+
+    #[test]
+    fn object_stream() {
+        let objects: &[u8] = b"%PDF-1.5\n\
+            1 0 obj\n<< /Type /Catalog >>\nendobj\n\
+            2 0 obj\n<< /Type /ObjStm /N 1 /First 4 /Length 23 >>\nstream\n\
+            3 0 << /Title (Meme) >>\nendstream\nendobj\n";
+        let xref: &[u8] = b"4 0 obj\n<< /Type /XRef /Size 5 /W [1 1 1] /Root 1 0 R /Info 3 0 R \
+            /Length 15 >>\nstream\n\x00\x00\xFF\x01\x09\x00\x01\x2D\x00\x02\x02\x00\x01\x92\x00\
+            \nendstream\nendobj\nstartxref\n146\n%%EOF";
+        let src = &[objects, xref].concat();
+        let dest = write(src, &["foo"]);
+        let (_, info) =
+            Document::read(&mut Cursor::new(&dest)).unwrap().info(&mut Cursor::new(&dest)).unwrap();
+        assert_eq!(info.get(b"Title"), Some(&Object::String(b"Meme".to_vec())));
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn encrypted() {
+        let trailer = b"trailer\n<< /Size 2 /Root 1 0 R /Encrypt << >> >>\nstartxref\n45\n%%EOF";
+        let src = &[BODY, XREF, trailer].concat();
+        let result = write_tags(&mut Cursor::new(src), &mut std::io::sink(), ["foo"]);
+        assert!(matches!(result, Err(Error::PdfEncrypted)));
+    }
+}
+
+crate::utils::standard_tests!("pdf");
@@ -43,7 +43,7 @@ impl<'a, T: Read + Seek> Checksum<'a, T> {
     }
 }
 
-impl<'a, T: Read + Seek> Read for Checksum<'a, T> {
+impl<T: Read + Seek> Read for Checksum<'_, T> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let n = self.src.read(buf)?;
         self.digest.update(buf);
@@ -0,0 +1,205 @@
+//! # Photoshop Document
+//!
+//! PSD files are made up of a series of sections, each one prefixed by its length:
+//!
+//! - 26 byte header, starting with the `8BPS` magic number and a version number, which is 1 for PSD
+//!   files, and 2 for the large document variant, PSB.
+//! - Color mode data, with a 4 byte big endian length.
+//! - Image resources, with a 4 byte big endian length.
+//! - Layer and mask information, with a 4 byte big endian length, or an 8 byte one in PSB files.
+//! - Image data, which lasts until the end of the file.
+//!
+//! The image resources section is itself a series of resource blocks, each structured as follows:
+//!
+//! - 4 byte signature, which is usually `8BIM`.
+//! - 2 byte big endian resource ID.
+//! - Pascal string with the name of the resource, padded to an even length.
+//! - 4 byte big endian length of the resource data.
+//! - The resource data itself, padded to an even length.
+//!
+//! Since the image resources section is laid out in the same way for PSD and PSB files, and no
+//! other section refers to it, both variants are handled the same.
+//!
+//! MemeDB stores its tags in an `8BIM` resource block with the ID `0x1337`, which lies in the range
+//! reserved for plug-ins, and the name `MemeDB`. The block is placed at the end of the image
+//! resources section.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for PSD](https://en.wikipedia.org/wiki/Adobe_Photoshop#File_format)
+//! - [Adobe Photoshop File Formats Specification](https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/)
+
+pub(crate) const MAGIC: &[u8] = b"8BPS";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, passthrough, read_byte, read_heap, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SIGNATURE: &[u8; 4] = b"8BIM";
+const TAGS_ID: u16 = 0x1337;
+const TAGS_NAME: &[u8] = b"MemeDB";
+
+const HEADER_SIZE: i64 = 26;
+
+fn padded(size: u64) -> u64 {
+    size + (size & 1)
+}
+
+struct Resource {
+    signature: [u8; 4],
+    id: u16,
+    name: Vec<u8>,
+    size: u32,
+}
+
+impl Resource {
+    fn read(src: &mut impl Read) -> Result<Self, std::io::Error> {
+        let signature = read_stack::<4>(src)?;
+        let id = u16::from_be_bytes(read_stack::<2>(src)?);
+        let name_len = read_byte(src)?;
+        // The length byte is counted when padding the name
+        let name = read_heap(src, padded(name_len as u64 + 1) as usize - 1)?;
+        let size = u32::from_be_bytes(read_stack::<4>(src)?);
+        Ok(Self { signature, id, name: name[..name_len as usize].to_vec(), size })
+    }
+
+    fn write(&self, dest: &mut impl Write) -> Result<(), std::io::Error> {
+        dest.write_all(&self.signature)?;
+        dest.write_all(&self.id.to_be_bytes())?;
+        dest.write_all(&[self.name.len() as u8])?;
+        dest.write_all(&self.name)?;
+        if self.name.len() & 1 == 0 {
+            dest.write_all(&[0])?;
+        }
+        dest.write_all(&self.size.to_be_bytes())
+    }
+
+    fn header_size(&self) -> u64 {
+        4 + 2 + padded(self.name.len() as u64 + 1) + 4
+    }
+
+    fn is_tags(&self) -> bool {
+        &self.signature == SIGNATURE && self.id == TAGS_ID && self.name == TAGS_NAME
+    }
+}
+
+/// Skips the header and the color mode data, returning the length of the image resources section.
+fn resources_size(src: &mut (impl Read + Seek)) -> Result<u64, std::io::Error> {
+    skip(src, HEADER_SIZE)?;
+    let color_mode_size = u32::from_be_bytes(read_stack::<4>(src)?);
+    skip(src, color_mode_size as i64)?;
+    Ok(u32::from_be_bytes(read_stack::<4>(src)?) as u64)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let end = resources_size(src)? + src.stream_position()?;
+    while src.stream_position()? < end {
+        let resource = Resource::read(src)?;
+        if resource.is_tags() {
+            return decode_tags(src);
+        }
+        skip(src, padded(resource.size as u64) as i64)?;
+    }
+    Ok(Vec::new())
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+    let tags_size = u32::try_from(tags_bytes.len()).map_err(|_| Error::TagsTooLarge)?;
+    let tags =
+        Resource { signature: *SIGNATURE, id: TAGS_ID, name: TAGS_NAME.to_vec(), size: tags_size };
+
+    // A first pass over the resource headers lets us know the new length of the section
+    let end = resources_size(src)? + src.stream_position()?;
+    if end > len {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    let first = src.stream_position()?;
+    let mut size = tags.header_size() + padded(tags_size as u64);
+    while src.stream_position()? < end {
+        let resource = Resource::read(src)?;
+        let next = src.stream_position()? + padded(resource.size as u64);
+        if next > end {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        if !resource.is_tags() {
+            size += resource.header_size() + padded(resource.size as u64);
+        }
+        src.seek(SeekFrom::Start(next))?;
+    }
+    let size = u32::try_from(size).map_err(|_| Error::TagsTooLarge)?;
+
+    src.seek(SeekFrom::Start(start))?;
+    passthrough(src, dest, first - 4 - start)?;
+    dest.write_all(&size.to_be_bytes())?;
+    src.seek(SeekFrom::Start(first))?;
+    while src.stream_position()? < end {
+        let resource = Resource::read(src)?;
+        if resource.is_tags() {
+            skip(src, padded(resource.size as u64) as i64)?;
+        } else {
+            resource.write(dest)?;
+            passthrough(src, dest, padded(resource.size as u64))?;
+        }
+    }
+    tags.write(dest)?;
+    dest.write_all(&tags_bytes)?;
+    if tags_size & 1 == 1 {
+        dest.write_all(&[0])?;
+    }
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &[&[u8]] = &[MAGIC, &[0, 1], &[0; 20], &[0; 4]];
+    const RESOURCE: &[&[u8]] = &[SIGNATURE, &[0x03, 0xED], &[1, b'a'], &[0, 0, 0, 1], &[0xFF, 0]];
+    const TAGS: &[&[u8]] = &[SIGNATURE, &[0x13, 0x37], &[6], TAGS_NAME, &[0], &[0, 0, 0, 5]];
+    const TAGS_DATA: &[u8] = &[0x83, b'f', b'o', b'o', 0x00, 0x00];
+    const LAYERS: &[u8] = &[0, 0, 0, 0];
+
+    #[test]
+    fn padding() {
+        let src = &[HEADER, &[&[0, 0, 0, 14]], RESOURCE, &[LAYERS]].concat().concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let size: &[u8] = &[0, 0, 0, 38];
+        let expected = &[HEADER, &[size], RESOURCE, TAGS, &[TAGS_DATA, LAYERS]].concat().concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn foreign_resource_id() {
+        // Another plug-in may use the same ID, but it won't use the same name
+        let foreign: &[&[u8]] = &[SIGNATURE, &[0x13, 0x37], &[1, b'a'], &[0, 0, 0, 1], &[0x80, 0]];
+        let src = &[HEADER, &[&[0, 0, 0, 14]], foreign, &[LAYERS]].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        assert_eq!(&dest[34..48], foreign.concat());
+    }
+}
+
+crate::utils::standard_tests!("psd");
+crate::utils::standard_tests!("psb", psb_standard_tests);
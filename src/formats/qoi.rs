@@ -0,0 +1,141 @@
+//! # Quite OK Image Format
+//!
+//! QOI files start with a 14 byte header, structured as follows:
+//!
+//! - 4 byte `qoif` magic number.
+//! - 4 byte big endian width.
+//! - 4 byte big endian height.
+//! - 1 byte number of channels.
+//! - 1 byte colorspace.
+//!
+//! This is followed by a stream of chunks, each one describing one or more pixels, and then by an
+//! 8 byte end marker, made up of seven `0x00` bytes and a `0x01` byte. The chunks have no length
+//! prefix, and the end marker is itself a valid sequence of chunks, so the only way to find the end
+//! of the image is to go through every chunk while counting pixels.
+//!
+//! MemeDB stores its tags right after the end marker, prefixed by the `MemeDB\0` identifier.
+//! Decoders that follow the specification stop reading once every pixel has been decoded, so they
+//! ignore any data that follows the image, including the tags.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for QOI](https://en.wikipedia.org/wiki/QOI_(image_format))
+//! - [QOI specification](https://qoiformat.org/qoi-specification.pdf)
+
+pub(crate) const MAGIC: &[u8] = b"qoif";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_byte, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_ID: &[u8; 7] = b"MemeDB\x00";
+const END_MARKER: &[u8; 8] = &[0, 0, 0, 0, 0, 0, 0, 1];
+
+const QOI_OP_RGB: u8 = 0b11111110;
+const QOI_OP_RGBA: u8 = 0b11111111;
+const QOI_OP_LUMA: u8 = 0b10000000;
+const QOI_OP_RUN: u8 = 0b11000000;
+const OP_MASK: u8 = 0b11000000;
+
+/// Goes through the header and every chunk, returning the position right after the end marker.
+fn data_end(src: &mut (impl Read + Seek)) -> Result<u64, Error> {
+    skip(src, 4)?;
+    let width = u32::from_be_bytes(read_stack::<4>(src)?) as u64;
+    let height = u32::from_be_bytes(read_stack::<4>(src)?) as u64;
+    skip(src, 2)?;
+    let mut pixels = 0;
+    while pixels < width * height {
+        pixels += match read_byte(src)? {
+            QOI_OP_RGB => skip(src, 3).map(|_| 1)?,
+            QOI_OP_RGBA => skip(src, 4).map(|_| 1)?,
+            op if op & OP_MASK == QOI_OP_RUN => (op & !OP_MASK) as u64 + 1,
+            op if op & OP_MASK == QOI_OP_LUMA => skip(src, 1).map(|_| 1)?,
+            _ => 1,
+        };
+    }
+    if &read_stack::<8>(src)? != END_MARKER {
+        return Err(Error::QoiMissingEndMarker);
+    }
+    Ok(src.stream_position()?)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    data_end(src)?;
+    match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => decode_tags(src),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let end = data_end(src)?;
+
+    // Any data following the old tags is kept after the new ones
+    let rest = match or_eof(read_stack::<7>(src))? {
+        Some(id) if &id == TAGS_ID => {
+            decode_tags(src)?;
+            src.stream_position()?
+        }
+        _ => end,
+    };
+
+    src.seek(SeekFrom::Start(start))?;
+    passthrough(src, dest, end - start)?;
+    dest.write_all(TAGS_ID)?;
+    encode_tags(tags, dest)?;
+    src.seek(SeekFrom::Start(rest))?;
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A 2x2 image with three colored pixels and a run of one
+    const HEADER: &[&[u8]] = &[MAGIC, &[0, 0, 0, 2], &[0, 0, 0, 2], &[4, 0]];
+    const CHUNKS: &[&[u8]] = &[&[QOI_OP_RGBA, 0, 0, 0, 0], &[0x00, 0x00], &[QOI_OP_RUN]];
+    const TAGS: &[&[u8]] = &[TAGS_ID, &[0x83, b'f', b'o', b'o', 0x00]];
+
+    #[test]
+    fn end_marker_in_chunks() {
+        // Index chunks pointing to the first slot look just like the start of the end marker
+        let src = &[HEADER, CHUNKS, &[END_MARKER], TAGS].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn trailing_data() {
+        let src = &[HEADER, CHUNKS, &[END_MARKER, b"junk"]].concat().concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let expected = &[HEADER, CHUNKS, &[END_MARKER], TAGS, &[b"junk"]].concat().concat();
+        assert_eq!(&dest, expected);
+        let mut retagged = Vec::new();
+        write_tags(&mut Cursor::new(&dest), &mut retagged, ["foo"]).unwrap();
+        assert_eq!(&retagged, expected);
+    }
+
+    #[test]
+    fn missing_end_marker() {
+        let src = &[HEADER, CHUNKS, &[&[0, 0, 0, 0, 0, 0, 0, 0]]].concat().concat();
+        let result = read_tags(&mut Cursor::new(src));
+        assert!(matches!(result, Err(Error::QoiMissingEndMarker)));
+    }
+}
+
+crate::utils::standard_tests!("qoi");
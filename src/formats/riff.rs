@@ -11,35 +11,245 @@
 //! describing the format of the payload (`WEBP`, `AVI `, `WAV `, etc), and then a series of
 //! sub-chunks.
 //!
-//! MemeDB stores its tags in a `meme` chunk.
+//! RIFF is based on the older IFF format, whose files start with a `FORM` meta-chunk instead, and
+//! store lengths in big endian. Other than that, the structure is the same, so formats like AIFF
+//! (`AIFF` or `AIFC`) are also supported.
+//!
+//! Since sizes are only 32 bits long, there are a couple of extensions for larger files. OpenDML
+//! AVI files continue after the first `RIFF` meta-chunk with more `RIFF` meta-chunks of the type
+//! `AVIX`, whose data is referenced by absolute offsets. RF64 and BW64 files start with a `RF64` or
+//! `BW64` meta-chunk instead, whose first sub-chunk is a `ds64` chunk storing 64 bit sizes for the
+//! meta-chunk itself and for any sub-chunk whose size is set to `0xFFFFFFFF`.
+//!
+//! MemeDB stores its tags in a `meme` chunk, placed after every other chunk in the last meta-chunk,
+//! so that no data has to move. Old tags found in earlier meta-chunks are overwritten with a `JUNK`
+//! chunk of the same size.
+//!
+//! WebP files (`WEBP`) come in a simple format, which only contains a single `VP8 ` or `VP8L` image
+//! chunk, and an extended format, which starts with a `VP8X` chunk and allows for extra chunks.
+//! Since strict decoders reject unknown chunks in the simple format, simple files are promoted to
+//! the extended format when writing tags, by adding a `VP8X` chunk with the canvas size taken from
+//! the header of the image chunk.
 //!
 //! ## Relevant Links
 //!
 //! - [Wikipedia article for RIFF](https://en.wikipedia.org/wiki/Resource_Interchange_File_Format)
 //! - [WebP Container Specification](https://developers.google.com/speed/webp/docs/riff_container)
+//! - [Wikipedia article for IFF](https://en.wikipedia.org/wiki/Interchange_File_Format)
+//! - [OpenDML AVI File Format Extensions](http://www.jmcgowan.com/odmlff2.pdf)
+//! - [EBU Tech 3306, the RF64 specification](https://tech.ebu.ch/publications/tech3306)
+//! - [AIFF-C specification](http://www-mmsp.ece.mcgill.ca/Documents/AudioFormats/AIFF/Docs/AIFF-C.9.26.91.pdf)
 
 pub(crate) const MAGIC: &[u8] = b"RIFF";
 pub(crate) const OFFSET: usize = 0;
+pub(crate) const FORM_MAGIC: &[u8] = b"FORM";
+pub(crate) const RF64_MAGIC: &[u8] = b"RF64";
+pub(crate) const BW64_MAGIC: &[u8] = b"BW64";
 
 use crate::{
-    utils::{decode_tags, encode_tags, or_eof, passthrough, read_stack, skip},
+    utils::{decode_tags, encode_tags, or_eof, passthrough, read_exact_heap, read_stack, skip},
     Error,
 };
-use std::io::{Read, Seek, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 const TAGS_ID: &[u8; 4] = b"meme";
+const JUNK_ID: &[u8; 4] = b"JUNK";
+const DS64_ID: &[u8; 4] = b"ds64";
+const DATA_ID: &[u8; 4] = b"data";
+
+const WEBP: &[u8; 4] = b"WEBP";
+const VP8X_ID: &[u8; 4] = b"VP8X";
+const VP8X_ALPHA: u8 = 0x10;
+
+#[derive(Copy, Clone)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn new(magic: &[u8]) -> Self {
+        if magic.starts_with(FORM_MAGIC) {
+            Self::Big
+        } else {
+            Self::Little
+        }
+    }
+
+    fn u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32_bytes(self, n: u32) -> [u8; 4] {
+        match self {
+            Self::Little => n.to_le_bytes(),
+            Self::Big => n.to_be_bytes(),
+        }
+    }
+}
+
+fn padded(size: u64) -> u64 {
+    size + (size & 1)
+}
+
+/// Builds the `VP8X` chunk data for a simple WebP file, given the start of its only image chunk.
+fn webp_vp8x(chunk_id: [u8; 4], header: &[u8]) -> Option<[u8; 10]> {
+    let (width, height, alpha) = match &chunk_id {
+        b"VP8 " if header.len() >= 10 && header[3..6] == [0x9D, 0x01, 0x2A] => {
+            let width = u16::from_le_bytes([header[6], header[7]]) & 0x3FFF;
+            let height = u16::from_le_bytes([header[8], header[9]]) & 0x3FFF;
+            (width.checked_sub(1)? as u32, height.checked_sub(1)? as u32, false)
+        }
+        b"VP8L" if header.len() >= 5 && header[0] == 0x2F => {
+            let bits = u32::from_le_bytes(header[1..5].try_into().unwrap());
+            (bits & 0x3FFF, bits >> 14 & 0x3FFF, bits >> 28 & 1 == 1)
+        }
+        _ => return None,
+    };
+    let mut vp8x = [0; 10];
+    if alpha {
+        vp8x[0] = VP8X_ALPHA;
+    }
+    vp8x[4..7].copy_from_slice(&width.to_le_bytes()[..3]);
+    vp8x[7..10].copy_from_slice(&height.to_le_bytes()[..3]);
+    Some(vp8x)
+}
+
+/// The `ds64` chunk of RF64 and BW64 files, which holds the sizes that don't fit in 32 bits.
+struct Ds64 {
+    riff_size: u64,
+    data_size: u64,
+    table: Vec<([u8; 4], u64)>,
+}
+
+impl Ds64 {
+    fn read(src: &mut impl Read, size: u64) -> Result<Self, std::io::Error> {
+        if size < 28 {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        let bytes = read_exact_heap(src, size)?;
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let table_length = u32::from_le_bytes(bytes[24..28].try_into().unwrap()) as usize;
+        let table = bytes[28..].chunks_exact(12).take(table_length);
+        let table = table.map(|entry| {
+            let size = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+            (entry[0..4].try_into().unwrap(), size)
+        });
+        Ok(Self { riff_size: u64_at(0), data_size: u64_at(8), table: table.collect() })
+    }
+
+    /// Resolves the actual size of a chunk, whose 32 bit size is set to `-1` if it didn't fit.
+    fn chunk_size(&self, chunk_id: [u8; 4], size: u32) -> u64 {
+        if size != u32::MAX {
+            size as u64
+        } else if &chunk_id == DATA_ID {
+            self.data_size
+        } else {
+            let entry = self.table.iter().find(|(id, _)| *id == chunk_id);
+            entry.map_or(size as u64, |(_, size)| *size)
+        }
+    }
+}
+
+/// The header of a chunk, along with its actual size.
+struct Chunk {
+    id: [u8; 4],
+    size_bytes: [u8; 4],
+    size: u64,
+}
+
+/// A top level chunk like `RIFF`, which contains every other chunk.
+struct List {
+    header: [u8; 12],
+    ds64: Option<Ds64>,
+    /// Position of the first chunk inside.
+    start: u64,
+    end: u64,
+}
+
+impl List {
+    /// Reads the header of a list, given the `len` of the whole file.
+    fn read(src: &mut (impl Read + Seek), order: ByteOrder, len: u64) -> Result<Self, Error> {
+        let header = read_stack::<12>(src)?;
+        let start = src.stream_position()?;
+        let mut size = order.u32(header[4..8].try_into().unwrap()) as u64;
+        let mut ds64 = None;
+        if header.starts_with(RF64_MAGIC) || header.starts_with(BW64_MAGIC) {
+            let chunk_id = read_stack::<4>(src)?;
+            let chunk_size = order.u32(read_stack::<4>(src)?) as u64;
+            if &chunk_id != DS64_ID {
+                return Err(Error::RiffMissingDs64);
+            }
+            let chunk = Ds64::read(src, chunk_size)?;
+            size = chunk.riff_size;
+            ds64 = Some(chunk);
+            src.seek(SeekFrom::Start(start))?;
+        }
+        // Files written as a stream may not know their size, so they just use the whole file
+        let end = match (start - 4).checked_add(size) {
+            Some(end) if size != 0 && end <= len => end.max(start),
+            _ => len,
+        };
+        Ok(Self { header, ds64, start, end })
+    }
+
+    /// Reads the header of the chunk at the current position, if there's any left in the list.
+    fn read_chunk(
+        &self,
+        src: &mut (impl Read + Seek),
+        order: ByteOrder,
+    ) -> Result<Option<Chunk>, std::io::Error> {
+        if src.stream_position()? + 8 > self.end {
+            return Ok(None);
+        }
+        let id = read_stack::<4>(src)?;
+        let size_bytes = read_stack::<4>(src)?;
+        let size = order.u32(size_bytes);
+        let size = self.ds64.as_ref().map_or(size as u64, |ds64| ds64.chunk_size(id, size));
+        if src.stream_position()? + size > self.end {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        Ok(Some(Chunk { id, size_bytes, size }))
+    }
+}
+
+/// Reads the headers of every top level list.
+fn read_lists(src: &mut (impl Read + Seek)) -> Result<(ByteOrder, Vec<List>), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+    let magic = read_stack::<4>(src)?;
+    let order = ByteOrder::new(&magic);
+    src.seek(SeekFrom::Start(start))?;
+    let mut lists = vec![List::read(src, order, len)?];
+    // OpenDML AVI files keep going with more `RIFF` lists once the first one is full
+    loop {
+        let end = lists.last().unwrap().end;
+        src.seek(SeekFrom::Start(end))?;
+        match or_eof(read_stack::<4>(src))? {
+            Some(id) if id == magic => {
+                src.seek(SeekFrom::Start(end))?;
+                lists.push(List::read(src, order, len)?);
+            }
+            _ => break,
+        }
+    }
+    Ok((order, lists))
+}
 
 /// Given a `src`, return the tags contained inside.
 pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
-    let _ = read_stack::<12>(src)?; // We dont care about them, but they have to be there
-    while let Some(chunk_id) = or_eof(read_stack::<4>(src))? {
-        let chunk_size = u32::from_le_bytes(read_stack::<4>(src)?);
-        if &chunk_id == TAGS_ID {
-            return decode_tags(src);
-        }
-        skip(src, chunk_size as i64)?;
-        if chunk_size & 1 == 1 {
-            skip(src, 1)?;
+    let (order, lists) = read_lists(src)?;
+    for list in &lists {
+        src.seek(SeekFrom::Start(list.start))?;
+        while let Some(chunk) = list.read_chunk(src, order)? {
+            if &chunk.id == TAGS_ID {
+                return decode_tags(src);
+            }
+            skip(src, padded(chunk.size) as i64)?;
         }
     }
     Ok(Vec::new())
@@ -53,39 +263,101 @@ pub fn write_tags(
     dest: &mut impl Write,
     tags: impl IntoIterator<Item = impl AsRef<str>>,
 ) -> Result<(), Error> {
-    passthrough(src, dest, 4)?;
-    skip(src, 4)?;
-    let mut data = Vec::new();
-    passthrough(src, &mut data, 4)?;
-    while let Some(chunk_id) = or_eof(read_stack::<4>(src))? {
-        let chunk_size_bytes = read_stack::<4>(src)?;
-        let chunk_size = u32::from_le_bytes(chunk_size_bytes);
-        if &chunk_id == TAGS_ID {
-            skip(src, chunk_size as i64)?;
-            if chunk_size & 1 == 1 {
-                skip(src, 1)?;
-            }
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+
+    // A first pass over the chunk headers lets us know the final size without buffering anything
+    let (order, lists) = read_lists(src)?;
+    // Tags go at the end of the last list, so that nothing before them has to move
+    let last = lists.last().unwrap();
+    src.seek(SeekFrom::Start(last.start))?;
+    let mut size = 4 + 8 + padded(tags_bytes.len() as u64);
+    // Simple WebP files consist of a single image chunk, which is where the canvas size comes from
+    let mut vp8x = None;
+    if &last.header[8..12] == WEBP {
+        if let Some(chunk) = last.read_chunk(src, order)? {
+            let mut chunk_header = Vec::new();
+            src.take(chunk.size.min(10)).read_to_end(&mut chunk_header)?;
+            vp8x = webp_vp8x(chunk.id, &chunk_header);
+        }
+        src.seek(SeekFrom::Start(last.start))?;
+    }
+    if vp8x.is_some() {
+        size += 8 + 10;
+    }
+    while let Some(chunk) = last.read_chunk(src, order)? {
+        if &chunk.id != TAGS_ID {
+            size += 8 + padded(chunk.size);
+        }
+        skip(src, padded(chunk.size) as i64)?;
+    }
+    // RF64 files always store the size in the `ds64` chunk
+    let size_bytes = match last.ds64 {
+        Some(_) => last.header[4..8].try_into().unwrap(),
+        None => order.u32_bytes(u32::try_from(size).map_err(|_| Error::TagsTooLarge)?),
+    };
+
+    for (i, list) in lists.iter().enumerate() {
+        let is_last = i == lists.len() - 1;
+        if is_last {
+            dest.write_all(&list.header[0..4])?;
+            dest.write_all(&size_bytes)?;
+            dest.write_all(&list.header[8..12])?;
         } else {
-            data.extend(&chunk_id);
-            data.extend(&chunk_size_bytes);
-            if passthrough(src, &mut data, chunk_size as u64)? != chunk_size as u64 {
-                Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
-            };
-            if chunk_size & 1 == 1 {
-                data.write_all(&[0])?;
+            dest.write_all(&list.header)?;
+        }
+        if let (true, Some(vp8x)) = (is_last, vp8x) {
+            dest.write_all(VP8X_ID)?;
+            dest.write_all(&order.u32_bytes(vp8x.len() as u32))?;
+            dest.write_all(&vp8x)?;
+        }
+        src.seek(SeekFrom::Start(list.start))?;
+        while let Some(chunk) = list.read_chunk(src, order)? {
+            match &chunk.id {
+                TAGS_ID if is_last => {
+                    skip(src, padded(chunk.size) as i64)?;
+                    continue;
+                }
+                // Earlier lists can't change size, so old tags are blanked out instead
+                TAGS_ID => {
+                    dest.write_all(JUNK_ID)?;
+                    dest.write_all(&chunk.size_bytes)?;
+                    std::io::copy(&mut std::io::repeat(0).take(chunk.size), dest)?;
+                    skip(src, chunk.size as i64)?;
+                }
+                DS64_ID if is_last && list.ds64.is_some() => {
+                    let mut ds64 = read_exact_heap(src, chunk.size)?;
+                    ds64[0..8].copy_from_slice(&size.to_le_bytes());
+                    dest.write_all(&chunk.id)?;
+                    dest.write_all(&chunk.size_bytes)?;
+                    dest.write_all(&ds64)?;
+                }
+                _ => {
+                    dest.write_all(&chunk.id)?;
+                    dest.write_all(&chunk.size_bytes)?;
+                    passthrough(src, dest, chunk.size)?;
+                }
+            }
+            if chunk.size & 1 == 1 {
+                dest.write_all(&[0])?;
+                skip(src, 1)?;
             }
         }
+        if !is_last {
+            let position = src.stream_position()?;
+            passthrough(src, dest, list.end.saturating_sub(position))?;
+        }
     }
-    let mut tags_bytes = Vec::new();
-    encode_tags(tags, &mut tags_bytes)?;
-    data.extend(TAGS_ID);
-    data.extend(&(tags_bytes.len() as u32).to_le_bytes());
-    data.extend(&tags_bytes);
+    dest.write_all(TAGS_ID)?;
+    dest.write_all(&order.u32_bytes(tags_bytes.len() as u32))?;
+    dest.write_all(&tags_bytes)?;
     if tags_bytes.len() & 1 == 1 {
-        data.push(0);
+        dest.write_all(&[0])?;
     }
-    dest.write_all(&(data.len() as u32).to_le_bytes())?;
-    dest.write_all(&data)?;
+
+    // Anything after the last list is kept as is
+    src.seek(SeekFrom::Start(last.end))?;
+    std::io::copy(src, dest)?;
     Ok(())
 }
 
@@ -106,6 +378,77 @@ mod tests {
         let expected = &[MAGIC, &[0x18], &[0; 7], &ODD.concat(), &TAGS.concat()].concat();
         assert_eq!(&dest, expected);
     }
+
+    #[test]
+    fn chunk_after_odd_size_chunk() {
+        let src = &[MAGIC, &[0x18], &[0; 7], &ODD.concat(), &ODD.concat()].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let expected =
+            &[MAGIC, &[0x22], &[0; 7], &ODD.concat(), &ODD.concat(), &TAGS.concat()].concat();
+        assert_eq!(&dest, expected);
+    }
+
+    #[test]
+    fn webp_promotion() {
+        let image: &[&[u8]] = &[b"VP8L", &[0x05, 0, 0, 0], &[0x2F, 0x00, 0x40, 0x00, 0x10, 0x00]];
+        let src = &[MAGIC, &[0x12, 0, 0, 0], WEBP, &image.concat()].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        // A 1x2 canvas with an alpha channel
+        let extended: &[&[u8]] =
+            &[VP8X_ID, &[0x0A, 0, 0, 0], &[0x10, 0, 0, 0], &[0, 0, 0, 1, 0, 0]];
+        let expected = &[MAGIC, &[0x2E, 0, 0, 0], WEBP, &extended.concat(), &image.concat()];
+        assert_eq!(dest, [&expected.concat(), &TAGS.concat()[..]].concat());
+        // Already extended files are left as is
+        let mut again = Vec::new();
+        write_tags(&mut Cursor::new(&dest), &mut again, Vec::<String>::new()).unwrap();
+        assert_eq!(dest, again);
+    }
+
+    #[test]
+    fn open_dml() {
+        let first: &[&[u8]] = &[MAGIC, &[0x18, 0, 0, 0], b"AVI ", &ODD.concat(), &TAGS.concat()];
+        let second: &[&[u8]] = &[MAGIC, &[0x0E, 0, 0, 0], b"AVIX", &ODD.concat()];
+        let src = &[first.concat(), second.concat()].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        // The first list keeps its size, so that offsets into the second one stay valid
+        let junk: &[&[u8]] = &[JUNK_ID, &[0x01], &[0; 5]];
+        let first: &[&[u8]] = &[MAGIC, &[0x18, 0, 0, 0], b"AVI ", &ODD.concat(), &junk.concat()];
+        let second: &[&[u8]] = &[MAGIC, &[0x18, 0, 0, 0], b"AVIX", &ODD.concat(), &TAGS.concat()];
+        assert_eq!(dest, [first.concat(), second.concat()].concat());
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rf64() {
+        // The actual sizes are in `ds64`, including the one for the data chunk
+        let header: &[&[u8]] = &[RF64_MAGIC, &[0xFF; 4], b"WAVE", DS64_ID, &[28, 0, 0, 0]];
+        let sizes: &[&[u8]] = &[&4u64.to_le_bytes(), &[0; 12]];
+        let data: &[&[u8]] = &[DATA_ID, &[0xFF; 4], &[0; 4]];
+        let src = &[header, &[&0x34u64.to_le_bytes()], sizes, data].concat().concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let expected = &[header, &[&0x3Eu64.to_le_bytes()], sizes, data, TAGS].concat().concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn big_endian() {
+        let tags: &[&[u8]] = &[TAGS_ID, &[0, 0, 0, 1], &[0; 2]];
+        let odd: &[&[u8]] = &[&[0; 4], &[0, 0, 0, 1], &[0; 2]];
+        let src = &[FORM_MAGIC, &[0, 0, 0, 0x0E], b"AIFF", &odd.concat()].concat();
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let expected = &[FORM_MAGIC, &[0, 0, 0, 0x18], b"AIFF", &odd.concat(), &tags.concat()];
+        assert_eq!(dest, expected.concat());
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), Vec::<String>::new());
+    }
 }
 
 crate::utils::standard_tests!("webp");
+crate::utils::standard_tests!("aiff", aiff_standard_tests);
+crate::utils::standard_tests!("avi", avi_standard_tests);
+crate::utils::standard_tests!("wav", wav_standard_tests);
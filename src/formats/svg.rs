@@ -0,0 +1,357 @@
+//! # Scalable Vector Graphics
+//!
+//! SVG files are XML documents, whose root element is `<svg>`. Since they are plain text, they have
+//! no magic number of their own. Instead, the start of the document is parsed to find the root
+//! element, skipping over a byte order mark, the XML declaration, comments, processing instructions
+//! and the document type declaration. The root element has to show up within the first 16 KiB.
+//!
+//! MemeDB stores its tags in a `<metadata>` element placed as the first child of the root element,
+//! which declares the `memedb` namespace prefix. Each tag is stored as the text of a `<memedb:tag>`
+//! element. Only the bytes of that element are ever rewritten, so the rest of the document is kept
+//! exactly as it was.
+//!
+//! Some characters, like most ASCII control characters, can't be represented in XML 1.0 at all, so
+//! tags containing them can't be written.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for SVG](https://en.wikipedia.org/wiki/SVG)
+//! - [SVG 1.1 specification for metadata](https://www.w3.org/TR/SVG11/metadata.html)
+//! - [XML 1.0 specification](https://www.w3.org/TR/xml/)
+
+pub(crate) const MAGIC: &[u8] = b"<";
+pub(crate) const OFFSET: usize = 0;
+pub(crate) const BOM_MAGIC: &[u8] = b"\xEF\xBB\xBF";
+
+use crate::Error;
+use std::io::{Read, Write};
+
+const NAMESPACE: &[u8] = b"https://github.com/arguablykomodo/memedb_core";
+const METADATA: &[u8] = b"metadata";
+const NAMESPACE_ATTRIBUTE: &[u8] = b"xmlns:memedb";
+const TAG: &[u8] = b"memedb:tag";
+
+const DETECTION_LIMIT: u64 = 0x4000;
+
+struct StartTag<'a> {
+    name: &'a [u8],
+    attributes: Vec<(&'a [u8], &'a [u8])>,
+    self_closing: bool,
+    /// Position right after the closing `>`.
+    end: usize,
+}
+
+enum Markup<'a> {
+    Start(StartTag<'a>),
+    End,
+    /// Comments, processing instructions, CDATA sections and declarations.
+    Other,
+}
+
+fn find(bytes: &[u8], pos: usize, pattern: &[u8]) -> Option<usize> {
+    let i = bytes.get(pos..)?.windows(pattern.len()).position(|w| w == pattern)?;
+    Some(pos + i)
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+        pos += 1;
+    }
+    pos
+}
+
+fn is_name_end(byte: u8) -> bool {
+    byte.is_ascii_whitespace() || matches!(byte, b'/' | b'>' | b'=')
+}
+
+/// Parses a start tag, given the position right after its `<`.
+fn parse_start_tag(bytes: &[u8], pos: usize) -> Option<StartTag<'_>> {
+    let name_end = pos + bytes.get(pos..)?.iter().position(|b| is_name_end(*b))?;
+    let name = &bytes[pos..name_end];
+    let mut attributes = Vec::new();
+    let mut pos = name_end;
+    loop {
+        pos = skip_whitespace(bytes, pos);
+        match bytes.get(pos..)? {
+            [b'/', b'>', ..] => {
+                return Some(StartTag { name, attributes, self_closing: true, end: pos + 2 })
+            }
+            [b'>', ..] => {
+                return Some(StartTag { name, attributes, self_closing: false, end: pos + 1 })
+            }
+            _ => {
+                let name_end = pos + bytes[pos..].iter().position(|b| is_name_end(*b))?;
+                let attribute = &bytes[pos..name_end];
+                pos = skip_whitespace(bytes, name_end);
+                if bytes.get(pos) != Some(&b'=') || attribute.is_empty() {
+                    return None;
+                }
+                pos = skip_whitespace(bytes, pos + 1);
+                let quote = *bytes.get(pos).filter(|b| matches!(b, b'"' | b'\''))?;
+                let value_end = pos + 1 + bytes[pos + 1..].iter().position(|b| *b == quote)?;
+                attributes.push((attribute, &bytes[pos + 1..value_end]));
+                pos = value_end + 1;
+            }
+        }
+    }
+}
+
+/// Parses the markup starting at `pos`, which must point at a `<`, returning it along with the
+/// position right after it.
+fn parse_markup(bytes: &[u8], pos: usize) -> Option<(Markup<'_>, usize)> {
+    let rest = bytes.get(pos..)?;
+    let other = |end: &[u8]| Some((Markup::Other, find(bytes, pos, end)? + end.len()));
+    if rest.starts_with(b"<!--") {
+        other(b"-->")
+    } else if rest.starts_with(b"<![CDATA[") {
+        other(b"]]>")
+    } else if rest.starts_with(b"<?") {
+        other(b"?>")
+    } else if rest.starts_with(b"<!") {
+        // Declarations may have an internal subset, which itself contains more markup
+        let mut i = pos + 2;
+        let mut depth = 0;
+        loop {
+            match bytes.get(i)? {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                b'"' | b'\'' => i = i + 1 + bytes[i + 1..].iter().position(|b| b == &bytes[i])?,
+                b'>' if depth == 0 => return Some((Markup::Other, i + 1)),
+                _ => (),
+            }
+            i += 1;
+        }
+    } else if rest.starts_with(b"</") {
+        other(b">").map(|(_, end)| (Markup::End, end))
+    } else if rest.starts_with(b"<") {
+        let tag = parse_start_tag(bytes, pos + 1)?;
+        let end = tag.end;
+        Some((Markup::Start(tag), end))
+    } else {
+        None
+    }
+}
+
+/// Finds the next piece of markup after `pos`, skipping over any text.
+fn next_markup(bytes: &[u8], pos: usize) -> Option<(usize, Markup<'_>, usize)> {
+    let start = find(bytes, pos, b"<")?;
+    let (markup, end) = parse_markup(bytes, start)?;
+    Some((start, markup, end))
+}
+
+/// Finds the end of an element, given the position right after its start tag.
+fn element_end(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    loop {
+        let (_, markup, end) = next_markup(bytes, pos)?;
+        match markup {
+            Markup::Start(tag) if !tag.self_closing => depth += 1,
+            Markup::End if depth == 0 => return Some(end),
+            Markup::End => depth -= 1,
+            _ => (),
+        }
+        pos = end;
+    }
+}
+
+/// Finds the root element, skipping over everything that may come before it.
+fn find_root(bytes: &[u8]) -> Option<StartTag<'_>> {
+    let mut pos = if bytes.starts_with(BOM_MAGIC) { BOM_MAGIC.len() } else { 0 };
+    loop {
+        pos = skip_whitespace(bytes, pos);
+        match parse_markup(bytes, pos)? {
+            (Markup::Start(tag), _) => return Some(tag).filter(|tag| tag.name == b"svg"),
+            (Markup::Other, end) => pos = end,
+            (Markup::End, _) => return None,
+        }
+    }
+}
+
+/// Finds the position of the MemeDB `<metadata>` element within the root element, if there's any.
+fn find_tags(bytes: &[u8], root: &StartTag) -> Option<(usize, usize)> {
+    let mut pos = root.end;
+    loop {
+        match next_markup(bytes, pos)? {
+            (start, Markup::Start(tag), end) => {
+                let end = if tag.self_closing { end } else { element_end(bytes, end)? };
+                let is_tags = tag.name == METADATA
+                    && tag.attributes.contains(&(NAMESPACE_ATTRIBUTE, NAMESPACE));
+                if is_tags {
+                    return Some((start, end));
+                }
+                pos = end;
+            }
+            (_, Markup::End, _) => return None,
+            (_, Markup::Other, end) => pos = end,
+        }
+    }
+}
+
+/// Checks that `header` and the bytes following it are the start of an SVG document.
+pub(crate) fn is_svg(header: &[u8], src: &mut dyn Read) -> Result<bool, std::io::Error> {
+    let mut bytes = header.to_vec();
+    src.take(DETECTION_LIMIT).read_to_end(&mut bytes)?;
+    Ok(find_root(&bytes).is_some())
+}
+
+fn decode_text(text: &[u8]) -> Result<String, Error> {
+    let text = String::from_utf8(text.to_vec())?;
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(i) = rest.find('&') {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let Some(end) = rest.find(';') else { break };
+        let reference = match &rest[1..end] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            r if r.starts_with("#x") => {
+                u32::from_str_radix(&r[2..], 16).ok().and_then(char::from_u32)
+            }
+            r if r.starts_with('#') => r[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        if let Some(c) = reference {
+            decoded.push(c);
+            rest = &rest[end + 1..];
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
+        }
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+fn encode_text(text: &str, dest: &mut Vec<u8>) -> Result<(), Error> {
+    for c in text.chars() {
+        match c {
+            '<' => dest.extend_from_slice(b"&lt;"),
+            '>' => dest.extend_from_slice(b"&gt;"),
+            '&' => dest.extend_from_slice(b"&amp;"),
+            // Line endings are normalized by XML parsers, so carriage returns have to be escaped
+            '\r' => dest.extend_from_slice(b"&#13;"),
+            '\t' | '\n' | '\u{20}'..='\u{FFFD}' | '\u{10000}'..='\u{10FFFF}' => {
+                dest.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            _ => return Err(Error::SvgInvalidCharacter(c)),
+        }
+    }
+    Ok(())
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut impl Read) -> Result<Vec<String>, Error> {
+    let mut bytes = Vec::new();
+    src.read_to_end(&mut bytes)?;
+    let root = find_root(&bytes).ok_or(Error::SvgInvalidDocument)?;
+    let Some((start, end)) = find_tags(&bytes, &root) else {
+        return Ok(Vec::new());
+    };
+    let mut tags = Vec::new();
+    let (_, mut pos) = parse_markup(&bytes, start).ok_or(Error::SvgInvalidDocument)?;
+    while let Some((_, markup, markup_end)) = next_markup(&bytes[..end], pos) {
+        if let Markup::Start(StartTag { name: TAG, self_closing, .. }) = markup {
+            if self_closing {
+                tags.push(String::new());
+            } else {
+                let text_end = find(&bytes, markup_end, b"<").ok_or(Error::SvgInvalidDocument)?;
+                tags.push(decode_text(&bytes[markup_end..text_end])?);
+            }
+        }
+        pos = markup_end;
+    }
+    Ok(tags)
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut impl Read,
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    src.read_to_end(&mut bytes)?;
+    let root = find_root(&bytes).ok_or(Error::SvgInvalidDocument)?;
+
+    let mut element = [b"<metadata ", NAMESPACE_ATTRIBUTE, b"=\"", NAMESPACE, b"\">"].concat();
+    for tag in tags {
+        element.extend_from_slice(b"<memedb:tag>");
+        encode_text(tag.as_ref(), &mut element)?;
+        element.extend_from_slice(b"</memedb:tag>");
+    }
+    element.extend_from_slice(b"</metadata>");
+
+    if let Some((start, end)) = find_tags(&bytes, &root) {
+        dest.write_all(&bytes[..start])?;
+        dest.write_all(&element)?;
+        dest.write_all(&bytes[end..])?;
+    } else if root.self_closing {
+        // The root element has no children yet, so it has to be split into a start and end tag
+        dest.write_all(&bytes[..root.end - 2])?;
+        dest.write_all(b">")?;
+        dest.write_all(&element)?;
+        dest.write_all(b"</svg>")?;
+        dest.write_all(&bytes[root.end..])?;
+    } else {
+        dest.write_all(&bytes[..root.end])?;
+        dest.write_all(&element)?;
+        dest.write_all(&bytes[root.end..])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identify_format, Format};
+
+    const METADATA_START: &[u8] =
+        b"<metadata xmlns:memedb=\"https://github.com/arguablykomodo/memedb_core\">";
+    const TAGS: &[&[u8]] =
+        &[METADATA_START, b"<memedb:tag>a &lt;b&gt; &amp;c&#13;</memedb:tag></metadata>"];
+
+    #[test]
+    fn prolog() {
+        let src = &[
+            BOM_MAGIC,
+            b"<?xml version=\"1.0\"?>\n<!-- <svg> -->\n",
+            b"<!DOCTYPE svg [<!ENTITY e \"<foo>\">]>\n<svg/>",
+        ]
+        .concat();
+        assert_eq!(identify_format(&mut &src[..]).unwrap(), Some(Format::Svg));
+        assert!(!is_svg(b"<", &mut &b"html><svg/></html>"[..]).unwrap());
+        let mut dest = Vec::new();
+        write_tags(&mut &src[..], &mut dest, ["a <b> &c\r"]).unwrap();
+        assert_eq!(dest, [&src[..src.len() - 2], b">", &TAGS.concat(), b"</svg>"].concat());
+        assert_eq!(read_tags(&mut &dest[..]).unwrap(), ["a <b> &c\r"]);
+    }
+
+    #[test]
+    fn preserve_bytes() {
+        let start: &[u8] =
+            b"<svg\n  width='1'>\n  <metadata><rdf:RDF/></metadata>\n  <g><metadata ";
+        let nested: &[u8] =
+            b"xmlns:memedb=\"https://github.com/arguablykomodo/memedb_core\"/></g>\n  ";
+        let end: &[u8] = b"\n</svg>\n";
+        let src = &[start, nested, &TAGS.concat(), end].concat();
+        assert_eq!(read_tags(&mut &src[..]).unwrap(), ["a <b> &c\r"]);
+        let mut dest = Vec::new();
+        write_tags(&mut &src[..], &mut dest, ["foo"]).unwrap();
+        let tags: &[u8] = b"<memedb:tag>foo</memedb:tag></metadata>";
+        assert_eq!(dest, [start, nested, METADATA_START, tags, end].concat());
+    }
+
+    #[test]
+    fn invalid_character() {
+        let result = write_tags(&mut &b"<svg/>"[..], &mut Vec::new(), ["\u{1B}"]);
+        assert!(matches!(result, Err(Error::SvgInvalidCharacter('\u{1B}'))));
+    }
+}
+
+crate::utils::standard_tests!("svg");
@@ -0,0 +1,222 @@
+//! # Tagged Image File Format
+//!
+//! TIFF files start with an 8 byte header made of:
+//!
+//! - 2 bytes describing the byte order of the file, `II` for little endian and `MM` for big endian.
+//! - The number 42, as a 2 byte number.
+//! - A 4 byte offset to the first Image File Directory (IFD).
+//!
+//! Each IFD is made of a 2 byte entry count, followed by 12 byte entries, followed by a 4 byte
+//! offset to the next IFD (or 0 if it's the last one). Each entry is structured as follows:
+//!
+//! - 2 byte tag identifying the entry.
+//! - 2 byte type of the values.
+//! - 4 byte count of values.
+//! - 4 byte value, or offset to the values if they don't fit in 4 bytes.
+//!
+//! Entries must be sorted by their tag. Since everything is referenced by absolute offsets, the
+//! same structure is also used by many camera RAW formats, like DNG, CR2 or NEF.
+//!
+//! MemeDB stores its tags in an entry with the private tag `65101`. To avoid moving any existing
+//! data, a copy of the first IFD with that entry added is written at the end of the file, and the
+//! header is updated to point to it. If the file already ends with such a copy, it is replaced.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for TIFF](https://en.wikipedia.org/wiki/TIFF)
+//! - [The TIFF 6.0 specification](https://www.itu.int/itudoc/itu-t/com16/tiff-fx/docs/tiff6.pdf)
+//! - [The DNG specification](https://helpx.adobe.com/camera-raw/digital-negative.html)
+
+pub(crate) const LE_MAGIC: &[u8] = b"II\x2A\x00";
+pub(crate) const BE_MAGIC: &[u8] = b"MM\x00\x2A";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, passthrough, read_heap, read_stack},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const TAGS_ENTRY: u16 = 65101;
+const UNDEFINED: u16 = 7;
+
+#[derive(Copy, Clone)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn new(header: &[u8]) -> Self {
+        if header.starts_with(b"II") {
+            Self::Little
+        } else {
+            Self::Big
+        }
+    }
+
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = bytes[..2].try_into().unwrap();
+        match self {
+            Self::Little => u16::from_le_bytes(bytes),
+            Self::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u16_bytes(self, n: u16) -> [u8; 2] {
+        match self {
+            Self::Little => n.to_le_bytes(),
+            Self::Big => n.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(self, n: u32) -> [u8; 4] {
+        match self {
+            Self::Little => n.to_le_bytes(),
+            Self::Big => n.to_be_bytes(),
+        }
+    }
+}
+
+fn padded(size: u64) -> u64 {
+    size + (size & 1)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let start = src.stream_position()?;
+    let header = read_stack::<8>(src)?;
+    let order = ByteOrder::new(&header);
+    src.seek(SeekFrom::Start(start + order.u32(&header[4..]) as u64))?;
+    let count = order.u16(&read_stack::<2>(src)?);
+    for _ in 0..count {
+        let entry = read_stack::<12>(src)?;
+        if order.u16(&entry[0..]) == TAGS_ENTRY {
+            let size = order.u32(&entry[4..]) as u64;
+            if size <= 4 {
+                return decode_tags(&mut &entry[8..8 + size as usize]);
+            }
+            src.seek(SeekFrom::Start(start + order.u32(&entry[8..]) as u64))?;
+            return decode_tags(&mut src.take(size));
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let start = src.stream_position()?;
+    let len = src.seek(SeekFrom::End(0))? - start;
+    src.seek(SeekFrom::Start(start))?;
+    let header = read_stack::<8>(src)?;
+    let order = ByteOrder::new(&header);
+
+    let ifd = order.u32(&header[4..]) as u64;
+    src.seek(SeekFrom::Start(start + ifd))?;
+    let count = order.u16(&read_stack::<2>(src)?);
+    let mut entries = read_heap(src, count as usize * 12)?;
+    let next_ifd = read_stack::<4>(src)?;
+
+    // If the file ends with an IFD we wrote before, we can drop it along with its tags
+    let mut end = len;
+    let tags_entry = entries.chunks(12).position(|e| order.u16(e) == TAGS_ENTRY);
+    if let Some(i) = tags_entry {
+        let entry = &entries[i * 12..(i + 1) * 12];
+        let (size, offset) = (order.u32(&entry[4..]) as u64, order.u32(&entry[8..]) as u64);
+        if ifd >= 8 && ifd + 2 + count as u64 * 12 + 4 == len {
+            end =
+                if size > 4 && offset >= 8 && offset + padded(size) == ifd { offset } else { ifd };
+        }
+        entries.drain(i * 12..(i + 1) * 12);
+    }
+
+    let mut tags_bytes = Vec::new();
+    encode_tags(tags, &mut tags_bytes)?;
+    let data_offset = padded(end);
+    let mut entry = Vec::with_capacity(12);
+    entry.extend(order.u16_bytes(TAGS_ENTRY));
+    entry.extend(order.u16_bytes(UNDEFINED));
+    entry.extend(order.u32_bytes(tags_bytes.len() as u32));
+    let new_ifd = if tags_bytes.len() <= 4 {
+        entry.extend(&tags_bytes);
+        entry.resize(12, 0);
+        tags_bytes.clear();
+        data_offset
+    } else {
+        let data_offset = u32::try_from(data_offset).map_err(|_| Error::TagsTooLarge)?;
+        entry.extend(order.u32_bytes(data_offset));
+        if tags_bytes.len() & 1 == 1 {
+            tags_bytes.push(0);
+        }
+        data_offset as u64 + tags_bytes.len() as u64
+    };
+    let new_ifd = u32::try_from(new_ifd).map_err(|_| Error::TagsTooLarge)?;
+    let i = entries.chunks(12).take_while(|e| order.u16(e) < TAGS_ENTRY).count();
+    entries.splice(i * 12..i * 12, entry);
+    let count = u16::try_from(entries.len() / 12).map_err(|_| Error::TagsTooLarge)?;
+
+    dest.write_all(&header[..4])?;
+    dest.write_all(&order.u32_bytes(new_ifd))?;
+    src.seek(SeekFrom::Start(start + 8))?;
+    let n = end.saturating_sub(8);
+    if passthrough(src, dest, n)? != n {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    if end & 1 == 1 {
+        dest.write_all(&[0])?;
+    }
+    dest.write_all(&tags_bytes)?;
+    dest.write_all(&order.u16_bytes(count))?;
+    dest.write_all(&entries)?;
+    dest.write_all(&next_ifd)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const ENTRY: &[u8] = &[0x01, 0x00, 0x00, 0x03, 0, 0, 0, 1, 0, 1, 0, 0];
+    const IFD: &[&[u8]] = &[&[0x00, 0x01], ENTRY, &[0; 4]];
+    const TAGS: &[&[u8]] = &[&[0xFE, 0x4D, 0x00, 0x07], &[0, 0, 0, 5], &[0, 0, 0, 26]];
+    const DATA: &[u8] = &[0x83, b'f', b'o', b'o', 0x00, 0x00];
+
+    #[test]
+    fn big_endian() {
+        let src = &[BE_MAGIC, &[0, 0, 0, 8], &IFD.concat()].concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, ["foo"]).unwrap();
+        let ifd: &[&[u8]] = &[&[0x00, 0x02], ENTRY, &TAGS.concat(), IFD[2]];
+        let expected = &[BE_MAGIC, &[0, 0, 0, 32], &IFD.concat(), DATA, &ifd.concat()].concat();
+        assert_eq!(&dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn full_ifd() {
+        let entries = ENTRY.repeat(0xFFFF);
+        let src = &[BE_MAGIC, &[0, 0, 0, 8], &[0xFF, 0xFF], &entries, &[0; 4]].concat();
+        let mut dest = Vec::new();
+        let result = write_tags(&mut Cursor::new(src), &mut dest, ["foo"]);
+        assert!(matches!(result, Err(Error::TagsTooLarge)));
+        assert!(dest.is_empty());
+    }
+}
+
+crate::utils::standard_tests!("tiff");
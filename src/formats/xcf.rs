@@ -0,0 +1,373 @@
+//! # eXperimental Computing Facility
+//!
+//! XCF is the native file format of GIMP. An XCF file is structured as follows:
+//!
+//! - A fixed-length header made of the string `gimp xcf `, a version (`file` or `vNNN`) followed
+//!   by a null byte, the canvas size, the color mode and, since version 4, the precision.
+//! - A list of image properties. Each property is made of a 4 byte big endian type, a 4 byte big
+//!   endian length, and the property data. The list ends with a property of type `0`.
+//! - A list of pointers to layers, ending with a null pointer.
+//! - A list of pointers to channels, ending with a null pointer.
+//! - The data for every layer and channel, which in turn contain pointers to their pixel data.
+//!
+//! Pointers are absolute offsets from the beginning of the file, 4 bytes long before version 11
+//! and 8 bytes long since.
+//!
+//! MemeDB stores its tags in an image parasite named `memedb-tags`, which lives in the image
+//! properties. Since this shifts every piece of data that follows, every pointer in the file is
+//! rewritten accordingly.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for XCF](https://en.wikipedia.org/wiki/XCF_(file_format))
+//! - [The XCF specification](https://gitlab.gnome.org/GNOME/gimp/-/blob/master/devel-docs/XCF.md)
+
+pub(crate) const MAGIC: &[u8] = b"gimp xcf ";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, passthrough, read_exact_heap, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const PROP_END: u32 = 0;
+const PROP_PARASITES: u32 = 21;
+
+const PARASITE_NAME: &[u8] = b"memedb-tags\x00";
+const PARASITE_PERSISTENT: u32 = 1;
+
+// Later versions can store additional pointers that this module does not know how to rewrite.
+const MAX_WRITE_VERSION: u32 = 21;
+
+fn read_version(src: &mut impl Read) -> Result<(u32, [u8; 4]), Error> {
+    let [version @ .., terminator] = read_stack::<5>(src)?;
+    match &version {
+        _ if terminator != 0 => Err(Error::XcfUnsupportedVersion(version)),
+        b"file" => Ok((0, version)),
+        [b'v', digits @ ..] if digits.iter().all(u8::is_ascii_digit) => {
+            Ok((digits.iter().fold(0, |n, d| n * 10 + (d - b'0') as u32), version))
+        }
+        _ => Err(Error::XcfUnsupportedVersion(version)),
+    }
+}
+
+fn header_size(version: u32) -> u64 {
+    if version >= 4 {
+        16
+    } else {
+        12
+    }
+}
+
+fn read_pointer(src: &mut impl Read, version: u32) -> Result<u64, std::io::Error> {
+    if version >= 11 {
+        Ok(u64::from_be_bytes(read_stack::<8>(src)?))
+    } else {
+        Ok(u32::from_be_bytes(read_stack::<4>(src)?) as u64)
+    }
+}
+
+fn write_pointer(dest: &mut impl Write, pointer: u64, version: u32) -> Result<(), std::io::Error> {
+    if version >= 11 {
+        dest.write_all(&pointer.to_be_bytes())
+    } else {
+        let pointer = u32::try_from(pointer)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+        dest.write_all(&pointer.to_be_bytes())
+    }
+}
+
+fn shift(pointer: u64, delta: i64) -> Result<u64, std::io::Error> {
+    pointer
+        .checked_add_signed(delta)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))
+}
+
+struct Parasite<'a> {
+    name: &'a [u8],
+    data: &'a [u8],
+    size: usize,
+}
+
+/// Parses the parasite found at the start of a `PROP_PARASITES` payload.
+fn parse_parasite(bytes: &[u8]) -> Result<Parasite<'_>, std::io::Error> {
+    let mut rest = bytes;
+    let mut split = |n: usize| {
+        if n > rest.len() {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok::<_, std::io::Error>(head)
+    };
+    let name_size = u32::from_be_bytes(split(4)?.try_into().unwrap());
+    let name = split(name_size as usize)?;
+    let _flags = split(4)?;
+    let data_size = u32::from_be_bytes(split(4)?.try_into().unwrap());
+    let data = split(data_size as usize)?;
+    Ok(Parasite { name, data, size: bytes.len() - rest.len() })
+}
+
+fn skip_props(src: &mut (impl Read + Seek)) -> Result<(), std::io::Error> {
+    loop {
+        let prop_type = u32::from_be_bytes(read_stack::<4>(src)?);
+        let prop_size = u32::from_be_bytes(read_stack::<4>(src)?);
+        skip(src, prop_size as i64)?;
+        if prop_type == PROP_END {
+            return Ok(());
+        }
+    }
+}
+
+fn read_pointer_list(src: &mut impl Read, version: u32) -> Result<Vec<u64>, std::io::Error> {
+    let mut pointers = Vec::new();
+    loop {
+        match read_pointer(src, version)? {
+            0 => return Ok(pointers),
+            pointer => pointers.push(pointer),
+        }
+    }
+}
+
+/// Walks through every layer and channel, returning the positions of all the non-null pointers
+/// found inside them.
+fn find_pointers<T: Read + Seek>(
+    src: &mut T,
+    version: u32,
+    layers: &[u64],
+    channels: &[u64],
+) -> Result<Vec<u64>, std::io::Error> {
+    let mut positions = Vec::new();
+    let mut read_tracked_pointer = |src: &mut T| {
+        let position = src.stream_position()?;
+        let pointer = read_pointer(src, version)?;
+        if pointer != 0 {
+            positions.push(position);
+        }
+        Ok::<_, std::io::Error>(pointer)
+    };
+
+    let mut channels = channels.to_vec();
+    let mut hierarchies = Vec::new();
+    for &layer in layers {
+        src.seek(SeekFrom::Start(layer))?;
+        skip(src, 12)?; // Width, height, and type
+        let name_size = u32::from_be_bytes(read_stack::<4>(src)?);
+        skip(src, name_size as i64)?;
+        skip_props(src)?;
+        hierarchies.push(read_tracked_pointer(src)?);
+        match read_tracked_pointer(src)? {
+            0 => {}
+            mask => channels.push(mask),
+        }
+    }
+    for channel in channels {
+        src.seek(SeekFrom::Start(channel))?;
+        skip(src, 8)?; // Width and height
+        let name_size = u32::from_be_bytes(read_stack::<4>(src)?);
+        skip(src, name_size as i64)?;
+        skip_props(src)?;
+        hierarchies.push(read_tracked_pointer(src)?);
+    }
+    let mut levels = Vec::new();
+    for hierarchy in hierarchies {
+        src.seek(SeekFrom::Start(hierarchy))?;
+        skip(src, 12)?; // Width, height, and bytes per pixel
+        if let level @ 1.. = read_tracked_pointer(src)? {
+            // Only the first level holds any data, the rest are dummies that GIMP ignores.
+            levels.push(level);
+            while read_tracked_pointer(src)? != 0 {}
+        }
+    }
+    for level in levels {
+        src.seek(SeekFrom::Start(level))?;
+        skip(src, 8)?; // Width and height
+        while read_tracked_pointer(src)? != 0 {}
+    }
+
+    positions.sort_unstable();
+    positions.dedup();
+    Ok(positions)
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    skip(src, MAGIC.len() as i64)?;
+    let (version, _) = read_version(src)?;
+    skip(src, header_size(version) as i64)?;
+    loop {
+        let prop_type = u32::from_be_bytes(read_stack::<4>(src)?);
+        let prop_size = u32::from_be_bytes(read_stack::<4>(src)?);
+        match prop_type {
+            PROP_END => return Ok(Vec::new()),
+            PROP_PARASITES => {
+                let parasites = read_exact_heap(src, prop_size as u64)?;
+                let mut parasites = parasites.as_slice();
+                while !parasites.is_empty() {
+                    let mut parasite = parse_parasite(parasites)?;
+                    if parasite.name == PARASITE_NAME {
+                        return decode_tags(&mut parasite.data);
+                    }
+                    parasites = &parasites[parasite.size..];
+                }
+            }
+            _ => {
+                skip(src, prop_size as i64)?;
+            }
+        }
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    passthrough(src, dest, MAGIC.len() as u64)?;
+    let (version, version_bytes) = read_version(src)?;
+    if version > MAX_WRITE_VERSION {
+        return Err(Error::XcfUnsupportedVersion(version_bytes));
+    }
+    dest.write_all(&version_bytes)?;
+    dest.write_all(&[0])?;
+    passthrough(src, dest, header_size(version))?;
+
+    // Properties are buffered so we know how much the rest of the file will be shifted by.
+    let props_start = src.stream_position()?;
+    let mut props = Vec::new();
+    loop {
+        let prop_type = u32::from_be_bytes(read_stack::<4>(src)?);
+        let prop_size = u32::from_be_bytes(read_stack::<4>(src)?);
+        match prop_type {
+            PROP_END => {
+                skip(src, prop_size as i64)?;
+                break;
+            }
+            PROP_PARASITES => {
+                let parasites = read_exact_heap(src, prop_size as u64)?;
+                let mut parasites = parasites.as_slice();
+                let mut kept = Vec::new();
+                while !parasites.is_empty() {
+                    let parasite = parse_parasite(parasites)?;
+                    if parasite.name != PARASITE_NAME {
+                        kept.extend_from_slice(&parasites[..parasite.size]);
+                    }
+                    parasites = &parasites[parasite.size..];
+                }
+                if !kept.is_empty() {
+                    props.extend(PROP_PARASITES.to_be_bytes());
+                    props.extend((kept.len() as u32).to_be_bytes());
+                    props.extend(kept);
+                }
+            }
+            _ => {
+                props.extend(prop_type.to_be_bytes());
+                props.extend(prop_size.to_be_bytes());
+                props.extend(read_exact_heap(src, prop_size as u64)?);
+            }
+        }
+    }
+    let mut tag_bytes = Vec::new();
+    encode_tags(tags, &mut tag_bytes)?;
+    let parasite_size = 4 + PARASITE_NAME.len() + 4 + 4 + tag_bytes.len();
+    props.extend(PROP_PARASITES.to_be_bytes());
+    props.extend((parasite_size as u32).to_be_bytes());
+    props.extend((PARASITE_NAME.len() as u32).to_be_bytes());
+    props.extend(PARASITE_NAME);
+    props.extend(PARASITE_PERSISTENT.to_be_bytes());
+    props.extend((tag_bytes.len() as u32).to_be_bytes());
+    props.extend(tag_bytes);
+    props.extend(PROP_END.to_be_bytes());
+    props.extend(0u32.to_be_bytes());
+    let delta = props.len() as i64 - (src.stream_position()? - props_start) as i64;
+    dest.write_all(&props)?;
+
+    let layers = read_pointer_list(src, version)?;
+    let channels = read_pointer_list(src, version)?;
+    for &pointer in layers.iter().chain(&[0]).chain(&channels).chain(&[0]) {
+        match pointer {
+            0 => write_pointer(dest, 0, version)?,
+            pointer => write_pointer(dest, shift(pointer, delta)?, version)?,
+        }
+    }
+
+    let mut position = src.stream_position()?;
+    let pointers = find_pointers(src, version, &layers, &channels)?;
+    src.seek(SeekFrom::Start(position))?;
+    for pointer in pointers {
+        if pointer < position {
+            Err(std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+        }
+        if passthrough(src, dest, pointer - position)? != pointer - position {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        }
+        let value = read_pointer(src, version)?;
+        write_pointer(dest, shift(value, delta)?, version)?;
+        position = src.stream_position()?;
+    }
+    std::io::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &[&[u8]] = &[MAGIC, b"v003\x00", &[0; 12]];
+    const END: &[u8] = &[0; 8];
+    const TAGS: &[&[u8]] = &[
+        &PROP_PARASITES.to_be_bytes(),
+        &25u32.to_be_bytes(),
+        &12u32.to_be_bytes(),
+        PARASITE_NAME,
+        &PARASITE_PERSISTENT.to_be_bytes(),
+        &1u32.to_be_bytes(),
+        &[0x00],
+    ];
+
+    #[test]
+    fn short_pointers() {
+        // One 1x1 channel with a single tile
+        let channel: &[&[u8]] = &[&[0, 0, 0, 1, 0, 0, 0, 1], &[0; 4], END, &[0, 0, 0, 70]];
+        let hierarchy: &[&[u8]] = &[&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1], &[0, 0, 0, 90], &[0; 4]];
+        let level: &[&[u8]] = &[&[0, 0, 0, 1, 0, 0, 0, 1], &[0, 0, 0, 106], &[0; 4], &[0xFF]];
+        let src = &[
+            &HEADER.concat(),
+            END,
+            &[0; 4],
+            &[0, 0, 0, 46],
+            &[0; 4],
+            &channel.concat(),
+            &hierarchy.concat(),
+            &level.concat(),
+        ]
+        .concat();
+        assert_eq!(read_tags(&mut Cursor::new(src)).unwrap(), Vec::<String>::new());
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(src), &mut dest, Vec::<String>::new()).unwrap();
+        let channel: &[&[u8]] = &[&[0, 0, 0, 1, 0, 0, 0, 1], &[0; 4], END, &[0, 0, 0, 103]];
+        let hierarchy: &[&[u8]] =
+            &[&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1], &[0, 0, 0, 123], &[0; 4]];
+        let level: &[&[u8]] = &[&[0, 0, 0, 1, 0, 0, 0, 1], &[0, 0, 0, 139], &[0; 4], &[0xFF]];
+        let expected = &[
+            &HEADER.concat(),
+            &TAGS.concat(),
+            END,
+            &[0; 4],
+            &[0, 0, 0, 79],
+            &[0; 4],
+            &channel.concat(),
+            &hierarchy.concat(),
+            &level.concat(),
+        ]
+        .concat();
+        assert_eq!(&dest, expected);
+    }
+}
+
+crate::utils::standard_tests!("xcf");
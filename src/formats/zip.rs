@@ -0,0 +1,354 @@
+//! # ZIP
+//!
+//! ZIP archives are made up of a series of entries, each one being a local file header starting
+//! with the `PK\x03\x04` magic number, followed by the file data. After the entries comes the
+//! central directory, which repeats the header of each entry along with the offset of its local
+//! file header. The archive ends with the end of central directory record, which holds the number
+//! of entries, the size and offset of the central directory, and an archive comment of up to 65535
+//! bytes. Readers find this record by searching backwards from the end of the archive, and then go
+//! through the central directory to find the entries.
+//!
+//! Many formats are ZIP archives with a specific layout, like comic book archives (CBZ), OpenRaster
+//! images, EPUB books, or OpenDocument files. Some of them require a `mimetype` entry to be the
+//! first one in the archive, so existing entries are never reordered.
+//!
+//! MemeDB stores its tags in an uncompressed `memedb/tags` entry, which is placed after every other
+//! entry, right before the central directory. The central directory is rewritten to include it, and
+//! the offsets of any entries that followed a previous tags entry are shifted accordingly. The
+//! archive comment is left alone, since many tools display it to the user.
+//!
+//! Archives spanning multiple disks or using ZIP64 extensions are not supported.
+//!
+//! ## Relevant Links
+//!
+//! - [Wikipedia article for ZIP](https://en.wikipedia.org/wiki/ZIP_(file_format))
+//! - [ZIP file format specification](https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT)
+
+pub(crate) const MAGIC: &[u8] = b"PK\x03\x04";
+pub(crate) const OFFSET: usize = 0;
+
+use crate::{
+    utils::{decode_tags, encode_tags, passthrough, read_exact_heap, read_stack, skip},
+    Error,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const CENTRAL_MAGIC: &[u8; 4] = b"PK\x01\x02";
+const END_MAGIC: &[u8; 4] = b"PK\x05\x06";
+const ZIP64_LOCATOR_MAGIC: &[u8; 4] = b"PK\x06\x07";
+const DESCRIPTOR_MAGIC: &[u8; 4] = b"PK\x07\x08";
+
+const TAGS_NAME: &[u8] = b"memedb/tags";
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+const MAX_COMMENT_SIZE: u64 = 0xFFFF;
+
+/// Version 1.0, which is enough for uncompressed entries.
+const VERSION: u16 = 10;
+/// January 1st, 1980, the earliest date that can be represented.
+const DATE: u16 = 0x0021;
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const METHOD_STORED: u16 = 0;
+
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+fn u16_at(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([bytes[i], bytes[i + 1]])
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+/// The end of central directory record, including the archive comment.
+struct End {
+    position: u64,
+    record: Vec<u8>,
+}
+
+impl End {
+    fn read(src: &mut (impl Read + Seek)) -> Result<Self, Error> {
+        let len = src.seek(SeekFrom::End(0))?;
+        let tail_start = len.saturating_sub(END_SIZE as u64 + MAX_COMMENT_SIZE);
+        src.seek(SeekFrom::Start(tail_start))?;
+        let mut tail = Vec::new();
+        src.read_to_end(&mut tail)?;
+        // The comment itself could contain the magic number, so its length has to add up too
+        let i = (0..(tail.len() + 1).saturating_sub(END_SIZE))
+            .rev()
+            .find(|i| {
+                tail[*i..].starts_with(END_MAGIC)
+                    && i + END_SIZE + u16_at(&tail, i + 20) as usize == tail.len()
+            })
+            .ok_or(Error::ZipInvalidArchive)?;
+        let end = Self { position: tail_start + i as u64, record: tail[i..].to_vec() };
+
+        let is_zip64 = end.position >= ZIP64_LOCATOR_SIZE && {
+            src.seek(SeekFrom::Start(end.position - ZIP64_LOCATOR_SIZE))?;
+            &read_stack::<4>(src)? == ZIP64_LOCATOR_MAGIC
+        };
+        let is_multi_disk = u16_at(&end.record, 4) != 0 || u16_at(&end.record, 6) != 0;
+        if is_zip64 || is_multi_disk {
+            return Err(Error::ZipUnsupportedArchive);
+        }
+        if end.directory_offset() + end.directory_size() > end.position {
+            return Err(Error::ZipInvalidArchive);
+        }
+        Ok(end)
+    }
+
+    fn entries(&self) -> u16 {
+        u16_at(&self.record, 10)
+    }
+
+    fn directory_size(&self) -> u64 {
+        u32_at(&self.record, 12) as u64
+    }
+
+    fn directory_offset(&self) -> u64 {
+        u32_at(&self.record, 16) as u64
+    }
+}
+
+/// An entry of the central directory.
+struct CentralHeader(Vec<u8>);
+
+impl CentralHeader {
+    fn read_all(src: &mut (impl Read + Seek), end: &End) -> Result<Vec<Self>, Error> {
+        src.seek(SeekFrom::Start(end.directory_offset()))?;
+        let directory = read_exact_heap(src, end.directory_size())?;
+        let mut rest = &directory[..];
+        let mut headers = Vec::new();
+        for _ in 0..end.entries() {
+            if rest.len() < CENTRAL_HEADER_SIZE || !rest.starts_with(CENTRAL_MAGIC) {
+                return Err(Error::ZipInvalidArchive);
+            }
+            let size = CENTRAL_HEADER_SIZE
+                + u16_at(rest, 28) as usize
+                + u16_at(rest, 30) as usize
+                + u16_at(rest, 32) as usize;
+            if rest.len() < size {
+                return Err(Error::ZipInvalidArchive);
+            }
+            headers.push(Self(rest[..size].to_vec()));
+            rest = &rest[size..];
+        }
+        Ok(headers)
+    }
+
+    fn flags(&self) -> u16 {
+        u16_at(&self.0, 8)
+    }
+
+    fn method(&self) -> u16 {
+        u16_at(&self.0, 10)
+    }
+
+    fn compressed_size(&self) -> u64 {
+        u32_at(&self.0, 20) as u64
+    }
+
+    fn local_offset(&self) -> u64 {
+        u32_at(&self.0, 42) as u64
+    }
+
+    fn set_local_offset(&mut self, offset: u32) {
+        self.0[42..46].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.0[CENTRAL_HEADER_SIZE..CENTRAL_HEADER_SIZE + u16_at(&self.0, 28) as usize]
+    }
+
+    /// Seeks to the data of the entry, returning the size of the whole entry.
+    fn seek_data(&self, src: &mut (impl Read + Seek)) -> Result<u64, Error> {
+        src.seek(SeekFrom::Start(self.local_offset()))?;
+        let header = read_stack::<LOCAL_HEADER_SIZE>(src)?;
+        if !header.starts_with(MAGIC) {
+            return Err(Error::ZipInvalidArchive);
+        }
+        let extra_size = u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+        let data_start = skip(src, extra_size as i64)?;
+        let mut size = LOCAL_HEADER_SIZE as u64 + extra_size + self.compressed_size();
+        if self.flags() & FLAG_DATA_DESCRIPTOR != 0 {
+            src.seek(SeekFrom::Start(self.local_offset() + size))?;
+            // The data descriptor may or may not start with a signature
+            size += if &read_stack::<4>(src)? == DESCRIPTOR_MAGIC { 16 } else { 12 };
+            src.seek(SeekFrom::Start(data_start))?;
+        }
+        Ok(size)
+    }
+}
+
+/// Given a `src`, return the tags contained inside.
+pub fn read_tags(src: &mut (impl Read + Seek)) -> Result<Vec<String>, Error> {
+    let end = End::read(src)?;
+    let headers = CentralHeader::read_all(src, &end)?;
+    match headers.iter().rfind(|h| h.name() == TAGS_NAME) {
+        Some(header) if header.method() != METHOD_STORED => Err(Error::ZipUnsupportedArchive),
+        Some(header) => {
+            header.seek_data(src)?;
+            decode_tags(src)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Read data from `src`, set the provided `tags`, and write to `dest`.
+///
+/// This function will remove any tags that previously existed in `src`.
+pub fn write_tags(
+    src: &mut (impl Read + Seek),
+    dest: &mut impl Write,
+    tags: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Error> {
+    let end = End::read(src)?;
+    let mut headers = CentralHeader::read_all(src, &end)?;
+    let directory_offset = end.directory_offset();
+
+    // The old tags entry is cut out, and every entry after it is moved back
+    let (removed_start, removed_size) = match headers.iter().rposition(|h| h.name() == TAGS_NAME) {
+        Some(i) => {
+            let header = headers.remove(i);
+            let size = header.seek_data(src)?;
+            if header.local_offset() + size > directory_offset {
+                return Err(Error::ZipInvalidArchive);
+            }
+            (header.local_offset(), size)
+        }
+        None => (directory_offset, 0),
+    };
+    for header in &mut headers {
+        if header.local_offset() >= removed_start + removed_size {
+            header.set_local_offset((header.local_offset() - removed_size) as u32);
+        } else if header.local_offset() > removed_start {
+            return Err(Error::ZipInvalidArchive);
+        }
+    }
+
+    let mut data = Vec::new();
+    encode_tags(tags, &mut data)?;
+    let data_size = u32::try_from(data.len()).map_err(|_| Error::TagsTooLarge)?;
+    let crc = CRC.checksum(&data);
+    let local_offset = (directory_offset - removed_size) as u32;
+
+    let mut local = Vec::with_capacity(LOCAL_HEADER_SIZE + TAGS_NAME.len());
+    local.extend_from_slice(MAGIC);
+    for field in [VERSION, 0, METHOD_STORED, 0, DATE] {
+        local.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [crc, data_size, data_size] {
+        local.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [TAGS_NAME.len() as u16, 0] {
+        local.extend_from_slice(&field.to_le_bytes());
+    }
+    local.extend_from_slice(TAGS_NAME);
+
+    let mut central = CENTRAL_MAGIC.to_vec();
+    central.extend_from_slice(&VERSION.to_le_bytes());
+    central.extend_from_slice(&local[4..LOCAL_HEADER_SIZE]);
+    central.extend_from_slice(&[0; 10]);
+    central.extend_from_slice(&local_offset.to_le_bytes());
+    central.extend_from_slice(TAGS_NAME);
+    headers.push(CentralHeader(central));
+
+    let new_directory_offset = local_offset as u64 + local.len() as u64 + data.len() as u64;
+    let new_directory_offset =
+        u32::try_from(new_directory_offset).map_err(|_| Error::TagsTooLarge)?;
+    let entries = u16::try_from(headers.len()).map_err(|_| Error::TagsTooLarge)?;
+    let directory_size = headers.iter().map(|h| h.0.len()).sum::<usize>();
+    let directory_size = u32::try_from(directory_size).map_err(|_| Error::TagsTooLarge)?;
+    let mut record = end.record.clone();
+    record[8..10].copy_from_slice(&entries.to_le_bytes());
+    record[10..12].copy_from_slice(&entries.to_le_bytes());
+    record[12..16].copy_from_slice(&directory_size.to_le_bytes());
+    record[16..20].copy_from_slice(&new_directory_offset.to_le_bytes());
+
+    src.seek(SeekFrom::Start(0))?;
+    passthrough(src, dest, removed_start)?;
+    src.seek(SeekFrom::Start(removed_start + removed_size))?;
+    passthrough(src, dest, directory_offset - removed_start - removed_size)?;
+    dest.write_all(&local)?;
+    dest.write_all(&data)?;
+    for header in &headers {
+        dest.write_all(&header.0)?;
+    }
+    // Anything between the central directory and its end record is kept as is
+    let directory_end = directory_offset + end.directory_size();
+    src.seek(SeekFrom::Start(directory_end))?;
+    passthrough(src, dest, end.position - directory_end)?;
+    dest.write_all(&record)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data) in entries {
+            let offset = archive.len() as u32;
+            archive.extend_from_slice(MAGIC);
+            archive.extend_from_slice(&[10, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+            archive.extend_from_slice(&CRC.checksum(data).to_le_bytes());
+            archive.extend_from_slice(&(data.len() as u32).to_le_bytes().repeat(2));
+            archive.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            archive.extend_from_slice(&[0, 0]);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(data);
+            directory.extend_from_slice(CENTRAL_MAGIC);
+            directory.extend_from_slice(&[10, 0]);
+            directory.extend_from_slice(&archive[offset as usize + 4..offset as usize + 30]);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let (offset, size) = (archive.len() as u32, directory.len() as u32);
+        archive.extend(directory);
+        archive.extend_from_slice(END_MAGIC);
+        archive.extend_from_slice(&[0, 0, 0, 0]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes().repeat(2));
+        archive.extend_from_slice(&size.to_le_bytes());
+        archive.extend_from_slice(&offset.to_le_bytes());
+        archive.extend_from_slice(&[3, 0]);
+        archive.extend_from_slice(b"PK\x05");
+        archive
+    }
+
+    #[test]
+    fn tags_entry() {
+        let tags: &[u8] = &[0x83, b'f', b'o', b'o', 0x00];
+        let src = archive(&[("mimetype", b"image/openraster"), ("a.png", b"png")]);
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, ["foo"]).unwrap();
+        let expected =
+            archive(&[("mimetype", b"image/openraster"), ("a.png", b"png"), ("memedb/tags", tags)]);
+        assert_eq!(dest, expected);
+        assert_eq!(read_tags(&mut Cursor::new(&dest)).unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn shifted_offsets() {
+        let tags: &[u8] = &[0x83, b'b', b'a', b'r', 0x00];
+        let src = archive(&[
+            ("mimetype", b"application/epub+zip"),
+            ("memedb/tags", &[0x00]),
+            ("a", b"a"),
+        ]);
+        let mut dest = Vec::new();
+        write_tags(&mut Cursor::new(&src), &mut dest, ["bar"]).unwrap();
+        let expected =
+            archive(&[("mimetype", b"application/epub+zip"), ("a", b"a"), ("memedb/tags", tags)]);
+        assert_eq!(dest, expected);
+    }
+}
+
+crate::utils::standard_tests!("zip");
+crate::utils::standard_tests!("ora", ora_standard_tests);
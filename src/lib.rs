@@ -17,11 +17,25 @@
 )]
 
 #[cfg(not(any(
+    feature = "bmp",
+    feature = "flac",
     feature = "gif",
+    feature = "ico",
     feature = "isobmff",
     feature = "jpeg",
+    feature = "jxl",
+    feature = "matroska",
+    feature = "mp3",
+    feature = "ogg",
+    feature = "pdf",
     feature = "png",
-    feature = "riff"
+    feature = "psd",
+    feature = "qoi",
+    feature = "riff",
+    feature = "svg",
+    feature = "tiff",
+    feature = "xcf",
+    feature = "zip"
 )))]
 compile_error!("At least one format feature must be enabled for this crate to be usable.");
 
@@ -31,26 +45,54 @@ mod utils;
 
 pub use error::Error;
 pub use formats::*;
-use std::io::{BufRead, Read, Seek, Write};
+use std::io::{BufRead, Seek, Write};
 
 /// Given a `src`, return the tags (if any) contained inside.
 ///
 /// This function operates by first calling [`identify_format`], and then calling the corresponding
 /// `read_tags` function if successful.
-pub fn read_tags(src: &mut (impl Read + BufRead + Seek)) -> Result<Option<Vec<String>>, Error> {
+pub fn read_tags(src: &mut (impl BufRead + Seek)) -> Result<Option<Vec<String>>, Error> {
     if let Some(format) = identify_format(src)? {
         src.seek(std::io::SeekFrom::Start(0))?;
         let tags = match format {
+            #[cfg(feature = "bmp")]
+            Format::Bmp => bmp::read_tags(src)?,
+            #[cfg(feature = "flac")]
+            Format::Flac => flac::read_tags(src)?,
             #[cfg(feature = "gif")]
             Format::Gif => gif::read_tags(src)?,
+            #[cfg(feature = "ico")]
+            Format::Ico => ico::read_tags(src)?,
             #[cfg(feature = "isobmff")]
             Format::Isobmff => isobmff::read_tags(src)?,
             #[cfg(feature = "jpeg")]
             Format::Jpeg => jpeg::read_tags(src)?,
+            #[cfg(feature = "jxl")]
+            Format::Jxl => jxl::read_tags(src)?,
+            #[cfg(feature = "matroska")]
+            Format::Matroska => matroska::read_tags(src)?,
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => mp3::read_tags(src)?,
+            #[cfg(feature = "ogg")]
+            Format::Ogg => ogg::read_tags(src)?,
+            #[cfg(feature = "pdf")]
+            Format::Pdf => pdf::read_tags(src)?,
             #[cfg(feature = "png")]
             Format::Png => png::read_tags(src)?,
+            #[cfg(feature = "psd")]
+            Format::Psd => psd::read_tags(src)?,
+            #[cfg(feature = "qoi")]
+            Format::Qoi => qoi::read_tags(src)?,
             #[cfg(feature = "riff")]
             Format::Riff => riff::read_tags(src)?,
+            #[cfg(feature = "svg")]
+            Format::Svg => svg::read_tags(src)?,
+            #[cfg(feature = "tiff")]
+            Format::Tiff => tiff::read_tags(src)?,
+            #[cfg(feature = "xcf")]
+            Format::Xcf => xcf::read_tags(src)?,
+            #[cfg(feature = "zip")]
+            Format::Zip => zip::read_tags(src)?,
         };
         Ok(Some(tags))
     } else {
@@ -65,24 +107,52 @@ pub fn read_tags(src: &mut (impl Read + BufRead + Seek)) -> Result<Option<Vec<St
 /// This function operates by first calling [`identify_format`], and then calling the corresponding
 /// `write_tags` function if successful.
 pub fn write_tags(
-    src: &mut (impl Read + BufRead + Seek),
+    src: &mut (impl BufRead + Seek),
     dest: &mut impl Write,
     tags: impl IntoIterator<Item = impl AsRef<str>>,
 ) -> Result<Option<()>, Error> {
     if let Some(format) = identify_format(src)? {
         src.seek(std::io::SeekFrom::Start(0))?;
         match format {
+            #[cfg(feature = "bmp")]
+            Format::Bmp => bmp::write_tags(src, dest, tags)?,
+            #[cfg(feature = "flac")]
+            Format::Flac => flac::write_tags(src, dest, tags)?,
             #[cfg(feature = "gif")]
             Format::Gif => gif::write_tags(src, dest, tags)?,
+            #[cfg(feature = "ico")]
+            Format::Ico => ico::write_tags(src, dest, tags)?,
             #[cfg(feature = "isobmff")]
             Format::Isobmff => isobmff::write_tags(src, dest, tags)?,
             #[cfg(feature = "jpeg")]
             Format::Jpeg => jpeg::write_tags(src, dest, tags)?,
+            #[cfg(feature = "jxl")]
+            Format::Jxl => jxl::write_tags(src, dest, tags)?,
+            #[cfg(feature = "matroska")]
+            Format::Matroska => matroska::write_tags(src, dest, tags)?,
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => mp3::write_tags(src, dest, tags)?,
+            #[cfg(feature = "ogg")]
+            Format::Ogg => ogg::write_tags(src, dest, tags)?,
+            #[cfg(feature = "pdf")]
+            Format::Pdf => pdf::write_tags(src, dest, tags)?,
             #[cfg(feature = "png")]
             Format::Png => png::write_tags(src, dest, tags)?,
+            #[cfg(feature = "psd")]
+            Format::Psd => psd::write_tags(src, dest, tags)?,
+            #[cfg(feature = "qoi")]
+            Format::Qoi => qoi::write_tags(src, dest, tags)?,
             #[cfg(feature = "riff")]
             Format::Riff => riff::write_tags(src, dest, tags)?,
-        };
+            #[cfg(feature = "svg")]
+            Format::Svg => svg::write_tags(src, dest, tags)?,
+            #[cfg(feature = "tiff")]
+            Format::Tiff => tiff::write_tags(src, dest, tags)?,
+            #[cfg(feature = "xcf")]
+            Format::Xcf => xcf::write_tags(src, dest, tags)?,
+            #[cfg(feature = "zip")]
+            Format::Zip => zip::write_tags(src, dest, tags)?,
+        }
         Ok(Some(()))
     } else {
         Ok(None)
@@ -18,6 +18,15 @@ pub fn read_heap(src: &mut impl Read, n: usize) -> Result<Vec<u8>, std::io::Erro
     Ok(bytes)
 }
 
+/// Like [`read_heap`], but for sizes that come from the file and could be absurdly large.
+pub fn read_exact_heap(src: &mut impl Read, n: u64) -> Result<Vec<u8>, std::io::Error> {
+    let mut bytes = Vec::new();
+    if src.take(n).read_to_end(&mut bytes)? as u64 != n {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    Ok(bytes)
+}
+
 pub fn skip(src: &mut impl std::io::Seek, n: i64) -> Result<u64, std::io::Error> {
     src.seek(std::io::SeekFrom::Current(n))
 }
@@ -66,7 +75,6 @@ pub fn decode_tags(src: &mut impl Read) -> Result<Vec<String>, crate::Error> {
             0b00000000 => return Ok(tags),
             0b00000001..=0b01111111 => {
                 passthrough(src, &mut tag_bytes, byte as u64)?;
-                continue;
             }
             0b10000000..=0b11111111 => {
                 passthrough(src, &mut tag_bytes, (byte & 0b01111111) as u64)?;
@@ -77,26 +85,16 @@ pub fn decode_tags(src: &mut impl Read) -> Result<Vec<String>, crate::Error> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use quickcheck_macros::quickcheck;
-
-    #[quickcheck]
-    fn qc_tags_identity(tags: Vec<String>) -> bool {
-        let mut buf = Vec::new();
-        encode_tags(&tags, &mut buf).unwrap();
-        decode_tags(&mut &buf[..]).unwrap() == tags
-    }
-}
-
 macro_rules! standard_tests {
     ($e:literal) => {
+        crate::utils::standard_tests!($e, standard_tests);
+    };
+    ($e:literal, $name:ident) => {
         #[cfg(test)]
-        mod standard_tests {
+        mod $name {
             use super::{read_tags, write_tags};
             use quickcheck_macros::quickcheck;
-            use std::io::{BufRead, Cursor, Read, Seek};
+            use std::io::{BufRead, Cursor, Seek};
 
             const UNTAGGED: &[u8] = include_bytes!(concat!("../../tests/media/minimal.", $e));
             const EMPTY: &[u8] = include_bytes!(concat!("../../tests/media/minimal_empty.", $e));
@@ -104,7 +102,7 @@ macro_rules! standard_tests {
             const LARGE: &[u8] = include_bytes!(concat!("../../tests/media/large.", $e));
 
             fn write(
-                src: &mut (impl Read + BufRead + Seek),
+                src: &mut (impl BufRead + Seek),
                 tags: impl IntoIterator<Item = impl AsRef<str>>,
             ) -> Vec<u8> {
                 let mut buf = Vec::new();
@@ -162,3 +160,17 @@ macro_rules! standard_tests {
 }
 
 pub(crate) use standard_tests;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    #[allow(clippy::needless_pass_by_value)]
+    fn qc_tags_identity(tags: Vec<String>) -> bool {
+        let mut buf = Vec::new();
+        encode_tags(&tags, &mut buf).unwrap();
+        decode_tags(&mut &buf[..]).unwrap() == tags
+    }
+}